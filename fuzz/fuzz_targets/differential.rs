@@ -0,0 +1,17 @@
+// Runs the interpreter and the reference interpreter side by side and fails
+// on the first instruction after which they disagree.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate rschip8;
+
+#[path = "../../tests/harness/mod.rs"]
+mod harness;
+
+use harness::{compare, Input};
+
+fuzz_target!(|data: &[u8]| {
+    if let Err(why) = compare(&Input::from_bytes(data)) {
+        panic!("{}", why);
+    }
+});
@@ -0,0 +1,15 @@
+// Any ROM, quirks, seed and key presses: the interpreter may report errors
+// but must never panic.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate rschip8;
+
+#[path = "../../tests/harness/mod.rs"]
+mod harness;
+
+use harness::{run_unchecked, Input};
+
+fuzz_target!(|data: &[u8]| {
+    run_unchecked(&Input::from_bytes(data));
+});
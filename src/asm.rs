@@ -0,0 +1,854 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+
+const PROGRAM_START: usize = 0x200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+// Directives that take the label in front of them as their own name rather
+// than as an address, or that make a bare word in column one a label.
+const LABELLED_DIRECTIVES: &[&str] = &["EQU", "=", "DB", "DW", "DA", "DS"];
+
+// Paul Robson's CHIP8 assembler, used by VBRIX.SRC.
+const ROBSON_MNEMONICS: &[&str] = &[
+    "MOV", "JMP", "JMI", "JSR", "RTS", "SKEQ", "SKNE", "SKPR", "SKUP", "RSB", "RANDOM",
+    "SPRITE", "HALT", "SCDOWN", "SCRIGHT", "SCLEFT", "MVI", "KEY", "GDELAY", "SDELAY",
+    "SSOUND", "ADI", "FONT", "XFONT", "BCD", "STR", "LDR",
+];
+
+const MNEMONICS: &[&str] = &[
+    "CLS", "RET", "SYS", "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB",
+    "SHR", "SUBN", "SHL", "RND", "DRW", "SKP", "SKNP", "SCD", "SCR", "SCL", "EXIT", "LOW",
+    "HIGH", "SCU", "SAVE", "LOAD", "PLANE", "AUDIO", "PITCH",
+    // Directives.
+    "EQU", "=", "DB", "DW", "DA", "DS", "ORG", "ALIGN", "OPTION", "DEFINE", "UNDEF", "IFDEF",
+    "IFUND", "ELSE", "ENDIF", "END", "USED", "XREF",
+];
+
+struct Line {
+    labels: Vec<String>,
+    mnemonic: Option<String>,
+    operands: Vec<String>,
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ';' if !quoted => return &text[..i],
+            _ => {},
+        }
+    }
+    text
+}
+
+// Splits on commas outside quotes and parentheses.
+fn split_operands(text: &str) -> Vec<String> {
+    let mut operands = Vec::new();
+    let (mut quoted, mut depth, mut start) = (false, 0, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                operands.push(text[start..i].trim().to_string());
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    // A trailing comma is allowed, as at the end of a long DB line.
+    let last = text[start..].trim();
+    if !last.is_empty() {
+        operands.push(last.to_string());
+    }
+    operands
+}
+
+fn next_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(|c: char| c.is_whitespace()).unwrap_or(text.len());
+    (&text[..end], &text[end..])
+}
+
+fn parse_line(text: &str) -> Line {
+    let mut rest = strip_comment(text).trim_end();
+    let mut labels = Vec::new();
+    // Labels end in a colon, which may run straight into the mnemonic.
+    loop {
+        let (word, _) = next_word(rest);
+        match word.find(':') {
+            Some(colon) if colon > 0 && !word.starts_with('\'') => {
+                labels.push(word[..colon].to_uppercase());
+                let skip = rest.find(':').unwrap() + 1;
+                rest = &rest[skip..];
+            },
+            _ => break,
+        }
+    }
+    let (mut word, mut after) = next_word(rest);
+    // A word without a colon is still a label when a data or symbol
+    // directive follows it, as in "XSTART EQU 23" or "SCORE DW 0".
+    let upper = word.to_uppercase();
+    if !word.is_empty() && !MNEMONICS.contains(&upper.as_str()) && !ROBSON_MNEMONICS.contains(&upper.as_str()) {
+        let (next, next_after) = next_word(after);
+        if LABELLED_DIRECTIVES.contains(&next.to_uppercase().as_str()) {
+            labels.push(word.to_uppercase());
+            word = next;
+            after = next_after;
+        }
+    }
+    let mnemonic = if word.is_empty() { None } else { Some(word.to_uppercase()) };
+    Line { labels, mnemonic, operands: split_operands(after) }
+}
+
+fn register(operand: &str) -> Option<usize> {
+    let upper = operand.to_uppercase();
+    let mut chars = upper.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V'), Some(digit), None) | (Some('R'), Some(digit), None) => digit.to_digit(16).map(|x| x as usize),
+        _ => None,
+    }
+}
+
+// The last register of a V0-VX range, as used by STR and LDR.
+fn range_end(operand: &str) -> &str {
+    match operand.find('-') {
+        Some(dash) => operand[dash + 1..].trim(),
+        None => operand,
+    }
+}
+
+// Rewrites Robson mnemonics as the Chipper instruction they stand for.
+fn translate(mnemonic: &str, operands: &[String]) -> (&'static str, Vec<String>) {
+    let with = |first: &str, ops: &[String]| {
+        let mut all = vec![first.to_string()];
+        all.extend_from_slice(ops);
+        all
+    };
+    let then = |ops: &[String], last: &str| {
+        let mut all = ops.to_vec();
+        all.push(last.to_string());
+        all
+    };
+    let ops = operands.to_vec();
+    match mnemonic {
+        "MOV" => ("LD", ops),
+        "JMP" => ("JP", ops),
+        "JMI" => ("JP", with("V0", operands)),
+        "JSR" => ("CALL", ops),
+        "RTS" => ("RET", ops),
+        "SKEQ" => ("SE", ops),
+        "SKNE" => ("SNE", ops),
+        "SKPR" => ("SKP", ops),
+        "SKUP" => ("SKNP", ops),
+        "RSB" => ("SUBN", ops),
+        "RANDOM" => ("RND", ops),
+        "SPRITE" => ("DRW", ops),
+        "HALT" => ("EXIT", ops),
+        "SCDOWN" => ("SCD", ops),
+        "SCRIGHT" => ("SCR", ops),
+        "SCLEFT" => ("SCL", ops),
+        "MVI" => ("LD", with("I", operands)),
+        "KEY" => ("LD", then(operands, "K")),
+        "GDELAY" => ("LD", then(operands, "DT")),
+        "SDELAY" => ("LD", with("DT", operands)),
+        "SSOUND" => ("LD", with("ST", operands)),
+        "ADI" => ("ADD", with("I", operands)),
+        "FONT" => ("LD", with("F", operands)),
+        "XFONT" => ("LD", with("HF", operands)),
+        "BCD" => ("LD", with("B", operands)),
+        "STR" => ("LD", vec!["[I]".to_string(), range_end(&ops.concat()).to_string()]),
+        "LDR" => ("LD", vec![range_end(&ops.concat()).to_string(), "[I]".to_string()]),
+        _ => ("", ops),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(usize, usize),
+    Here,
+    Op(char),
+    Shift(char),
+}
+
+struct Expr<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        let start = i;
+        i += 1;
+        match c {
+            ' ' | '\t' => {},
+            '#' => {
+                while i < bytes.len() && (bytes[i] as char).is_ascii_hexdigit() { i += 1; }
+                let value = i64::from_str_radix(&text[start + 1..i], 16)
+                    .map_err(|_| format!("bad hex number in '{}'", text))?;
+                tokens.push(Token::Number(value));
+            },
+            '$' => {
+                // Binary, with '.' allowed for 0 so sprites can be drawn.
+                let mut value = 0;
+                while i < bytes.len() && (bytes[i] == b'0' || bytes[i] == b'1' || bytes[i] == b'.') {
+                    value = (value << 1) | (bytes[i] == b'1') as i64;
+                    i += 1;
+                }
+                if i == start + 1 { return Err(format!("bad binary number in '{}'", text)); }
+                tokens.push(Token::Number(value));
+            },
+            '0'..='9' => {
+                while i < bytes.len() && (bytes[i] as char).is_ascii_alphanumeric() { i += 1; }
+                let digits = &text[start..i];
+                let value = if digits.starts_with("0x") || digits.starts_with("0X") {
+                    i64::from_str_radix(&digits[2..], 16)
+                } else {
+                    digits.parse()
+                };
+                tokens.push(Token::Number(value.map_err(|_| format!("bad number '{}'", digits))?));
+            },
+            '\'' => {
+                if i + 1 < bytes.len() && bytes[i + 1] == b'\'' {
+                    tokens.push(Token::Number(bytes[i] as i64));
+                    i += 2;
+                } else {
+                    return Err(format!("bad character constant in '{}'", text));
+                }
+            },
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                while i < bytes.len() && ((bytes[i] as char).is_ascii_alphanumeric() || bytes[i] == b'_') { i += 1; }
+                tokens.push(Token::Symbol(start, i));
+            },
+            // Chipper writes the current address as ?, Robson as '.'.
+            '?' | '.' => tokens.push(Token::Here),
+            '\\' => tokens.push(Token::Op('\\')),
+            '<' | '>' => {
+                // Chipper writes shifts with one character; allow C's two.
+                if i < bytes.len() && bytes[i] == bytes[start] { i += 1; }
+                tokens.push(Token::Shift(c));
+            },
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' | '(' | ')' => tokens.push(Token::Op(c)),
+            _ => return Err(format!("unexpected '{}' in '{}'", c, text)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Assembler {
+    symbols: HashMap<String, i64>,
+    defines: HashSet<String>,
+    // Undefined symbols count as 0 in the first pass, which only has to
+    // work out how long everything is.
+    first_pass: bool,
+    // Robson's assembler evaluates a - b - c as a - (b - c), and VBRIX
+    // depends on it. Sources that use his mnemonics start out grouping his
+    // way; OPTION RIGHTTOLEFT and OPTION LEFTTORIGHT override that from
+    // their line on.
+    right_to_left: bool,
+    // Set once a Robson mnemonic has been seen.
+    robson: bool,
+    origin: Option<usize>,
+    address: usize,
+    align: bool,
+    output: Vec<u8>,
+    pending_labels: Vec<String>,
+}
+
+impl Assembler {
+    fn new(first_pass: bool, symbols: HashMap<String, i64>, robson: bool) -> Self {
+        Assembler { symbols,
+                    defines: HashSet::new(),
+                    first_pass,
+                    right_to_left: robson,
+                    robson,
+                    origin: None,
+                    address: PROGRAM_START,
+                    align: true,
+                    output: Vec::new(),
+                    pending_labels: Vec::new(),
+        }
+    }
+
+    fn run(&mut self, source: &str) -> Result<(), AsmError> {
+        // Each IFDEF pushes whether its body is assembled.
+        let mut conditions: Vec<bool> = Vec::new();
+        for (index, text) in source.lines().enumerate() {
+            let error = |message| AsmError { line: index + 1, message };
+            let line = parse_line(text);
+            let active = conditions.iter().all(|&c| c);
+            let mnemonic = line.mnemonic.clone().unwrap_or_default();
+            let conditional = match mnemonic.as_str() {
+                "IFDEF" | "IFUND" => {
+                    let name = line.operands.first().map(|name| name.to_uppercase()).unwrap_or_default();
+                    conditions.push(self.defines.contains(&name) == (mnemonic == "IFDEF"));
+                    true
+                },
+                "ELSE" => match conditions.last_mut() {
+                    Some(condition) => { *condition = !*condition; true },
+                    None => return Err(error("ELSE without IFDEF".to_string())),
+                },
+                "ENDIF" => match conditions.pop() {
+                    Some(_) => true,
+                    None => return Err(error("ENDIF without IFDEF".to_string())),
+                },
+                _ => false,
+            };
+            if !active { continue; }
+            // A label in front of IFDEF belongs to whatever comes next.
+            if conditional {
+                self.pending_labels.extend(line.labels.iter().cloned());
+                continue;
+            }
+            if mnemonic == "END" { break; }
+            self.line(&line).map_err(error)?;
+        }
+        if !conditions.is_empty() {
+            return Err(AsmError { line: source.lines().count(), message: "IFDEF without ENDIF".to_string() });
+        }
+        self.bind_labels(self.address)
+            .map_err(|message| AsmError { line: source.lines().count(), message })
+    }
+
+    fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+        if self.first_pass && self.symbols.contains_key(name) {
+            return Err(format!("{} is defined twice", name));
+        }
+        self.symbols.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    // Labels name the address of whatever comes next, after any padding.
+    fn bind_labels(&mut self, address: usize) -> Result<(), String> {
+        for label in self.pending_labels.split_off(0) {
+            self.define(&label, address as i64)?;
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line: &Line) -> Result<(), String> {
+        let mnemonic = match line.mnemonic {
+            Some(ref mnemonic) => mnemonic.as_str(),
+            None => {
+                self.pending_labels.extend(line.labels.iter().cloned());
+                return Ok(());
+            },
+        };
+        let operands = &line.operands;
+        if mnemonic == "EQU" || mnemonic == "=" {
+            let name = match line.labels.last() {
+                Some(name) => name,
+                None => return Err(format!("{} needs a name", mnemonic)),
+            };
+            self.pending_labels.extend(line.labels[..line.labels.len() - 1].iter().cloned());
+            let text = operands.first().ok_or_else(|| format!("{} needs a value", mnemonic))?;
+            // A value that depends on a later label is settled in the second pass.
+            match self.evaluate(text, false) {
+                Ok(value) => self.define(name, value)?,
+                Err(_) if self.first_pass => {},
+                Err(why) => return Err(why),
+            }
+            return Ok(());
+        }
+        self.pending_labels.extend(line.labels.iter().cloned());
+        match mnemonic {
+            "DB" => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    if operand.starts_with('\'') && operand.len() > 3 {
+                        bytes.extend(self.string(operand)?);
+                    } else {
+                        bytes.push(self.byte(operand)?);
+                    }
+                }
+                self.emit(&bytes, false)
+            },
+            "DW" => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    let value = self.value(operand, -0x8000, 0xFFFF)?;
+                    bytes.push((value >> 8) as u8);
+                    bytes.push(value as u8);
+                }
+                self.emit(&bytes, false)
+            },
+            "DA" => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    bytes.extend(self.string(operand)?);
+                }
+                self.emit(&bytes, false)
+            },
+            "DS" => {
+                let count = self.value(self.single(operands)?, 0, 0xFFFF)?;
+                self.emit(&vec![0; count as usize], false)
+            },
+            "ORG" => {
+                let address = self.value(self.single(operands)?, 0, 0xFFFF)? as usize;
+                match self.origin {
+                    None => {
+                        self.origin = Some(address);
+                        self.address = address;
+                    },
+                    Some(_) if address >= self.address => {
+                        let padding = vec![0; address - self.address];
+                        self.emit(&padding, false)?;
+                    },
+                    Some(_) => return Err(format!("ORG {:X} is behind the current address {:X}", address, self.address)),
+                }
+                Ok(())
+            },
+            "ALIGN" => {
+                match operands.first().map(|o| o.to_uppercase()) {
+                    Some(ref setting) if setting == "ON" => self.align = true,
+                    Some(ref setting) if setting == "OFF" => self.align = false,
+                    _ => return Err("ALIGN takes ON or OFF".to_string()),
+                }
+                Ok(())
+            },
+            "DEFINE" => {
+                self.defines.insert(self.single(operands)?.to_uppercase());
+                Ok(())
+            },
+            "UNDEF" => {
+                self.defines.remove(&self.single(operands)?.to_uppercase());
+                Ok(())
+            },
+            "OPTION" => {
+                match operands.first().map(|setting| setting.to_uppercase()) {
+                    Some(ref setting) if setting == "RIGHTTOLEFT" => self.right_to_left = true,
+                    Some(ref setting) if setting == "LEFTTORIGHT" => self.right_to_left = false,
+                    // Listing and HP48 output settings have no effect on a
+                    // binary ROM.
+                    _ => {},
+                }
+                Ok(())
+            },
+            "USED" | "XREF" => Ok(()),
+            _ => {
+                if ROBSON_MNEMONICS.contains(&mnemonic) {
+                    self.robson = true;
+                }
+                let bytes = self.instruction(mnemonic, operands)?;
+                self.emit(&bytes, true)
+            },
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8], instruction: bool) -> Result<(), String> {
+        if self.origin.is_none() {
+            self.origin = Some(self.address);
+        }
+        if instruction && self.align && self.address % 2 == 1 {
+            self.output.push(0);
+            self.address += 1;
+        }
+        let address = self.address;
+        self.bind_labels(address)?;
+        self.output.extend_from_slice(bytes);
+        self.address += bytes.len();
+        Ok(())
+    }
+
+    fn single<'a>(&self, operands: &'a [String]) -> Result<&'a str, String> {
+        match operands {
+            [operand] => Ok(operand),
+            _ => Err(format!("expected one operand, got {}", operands.len())),
+        }
+    }
+
+    fn string(&self, operand: &str) -> Result<Vec<u8>, String> {
+        if operand.len() < 2 || !operand.starts_with('\'') || !operand.ends_with('\'') {
+            return Err(format!("bad string {}", operand));
+        }
+        // A doubled quote stands for one quote.
+        operand[1..operand.len() - 1].replace("''", "'").chars()
+            .map(|c| if (c as u32) < 0x100 { Ok(c as u8) } else { Err(format!("'{}' is not a byte", c)) })
+            .collect()
+    }
+
+    fn evaluate(&self, text: &str, lenient: bool) -> Result<i64, String> {
+        let mut expr = Expr { text, tokens: tokenize(text)?, position: 0 };
+        if expr.tokens.is_empty() {
+            return Err("missing value".to_string());
+        }
+        let value = self.or(&mut expr, lenient)?;
+        if expr.position != expr.tokens.len() {
+            return Err(format!("bad expression '{}'", text));
+        }
+        Ok(value)
+    }
+
+    fn value(&self, text: &str, min: i64, max: i64) -> Result<i64, String> {
+        let value = self.evaluate(text, self.first_pass)?;
+        if value < min || value > max {
+            return Err(format!("{} is out of range", text));
+        }
+        Ok(value)
+    }
+
+    // Negative bytes are stored as two's complement.
+    fn byte(&self, text: &str) -> Result<u8, String> {
+        Ok(self.value(text, -0x80, 0xFF)? as u8)
+    }
+
+    fn address(&self, text: &str) -> Result<u16, String> {
+        Ok(self.value(text, 0, 0xFFF)? as u16)
+    }
+
+    fn nibble(&self, text: &str) -> Result<u16, String> {
+        Ok(self.value(text, 0, 0xF)? as u16)
+    }
+
+    // Binary operators from loosest to tightest.
+    fn or(&self, expr: &mut Expr, lenient: bool) -> Result<i64, String> {
+        let mut value = self.xor(expr, lenient)?;
+        while expr.accept(Token::Op('|')) {
+            value |= self.xor(expr, lenient)?;
+        }
+        Ok(value)
+    }
+
+    fn xor(&self, expr: &mut Expr, lenient: bool) -> Result<i64, String> {
+        let mut value = self.and(expr, lenient)?;
+        while expr.accept(Token::Op('^')) {
+            value ^= self.and(expr, lenient)?;
+        }
+        Ok(value)
+    }
+
+    fn and(&self, expr: &mut Expr, lenient: bool) -> Result<i64, String> {
+        let mut value = self.shift(expr, lenient)?;
+        while expr.accept(Token::Op('&')) {
+            value &= self.shift(expr, lenient)?;
+        }
+        Ok(value)
+    }
+
+    fn shift(&self, expr: &mut Expr, lenient: bool) -> Result<i64, String> {
+        let mut value = self.quotient(expr, lenient)?;
+        loop {
+            if expr.accept(Token::Shift('<')) {
+                value <<= self.quotient(expr, lenient)?;
+            } else if expr.accept(Token::Shift('>')) {
+                value >>= self.quotient(expr, lenient)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    // Chipper's \ and % bind more loosely than + and -, so BLINKY can write
+    // MAZEEND - MAZE \ 4 for the number of maze entries.
+    fn quotient(&self, expr: &mut Expr, lenient: bool) -> Result<i64, String> {
+        let mut value = self.sum(expr, lenient)?;
+        loop {
+            let divide = if expr.accept(Token::Op('\\')) {
+                true
+            } else if expr.accept(Token::Op('%')) {
+                false
+            } else {
+                return Ok(value);
+            };
+            let divisor = self.sum(expr, lenient)?;
+            if divisor == 0 {
+                if lenient { continue; }
+                return Err(format!("division by zero in '{}'", expr.text));
+            }
+            value = if divide { value / divisor } else { value % divisor };
+        }
+    }
+
+    fn sum(&self, expr: &mut Expr, lenient: bool) -> Result<i64, String> {
+        let mut value = self.product(expr, lenient)?;
+        if self.right_to_left {
+            if expr.accept(Token::Op('+')) {
+                value += self.sum(expr, lenient)?;
+            } else if expr.accept(Token::Op('-')) {
+                value -= self.sum(expr, lenient)?;
+            }
+            return Ok(value);
+        }
+        loop {
+            if expr.accept(Token::Op('+')) {
+                value += self.product(expr, lenient)?;
+            } else if expr.accept(Token::Op('-')) {
+                value -= self.product(expr, lenient)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&self, expr: &mut Expr, lenient: bool) -> Result<i64, String> {
+        let mut value = self.unary(expr, lenient)?;
+        loop {
+            if expr.accept(Token::Op('*')) {
+                value *= self.unary(expr, lenient)?;
+            } else if expr.accept(Token::Op('/')) {
+                let divisor = self.unary(expr, lenient)?;
+                if divisor == 0 {
+                    if lenient { continue; }
+                    return Err(format!("division by zero in '{}'", expr.text));
+                }
+                value /= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&self, expr: &mut Expr, lenient: bool) -> Result<i64, String> {
+        if expr.accept(Token::Op('-')) {
+            Ok(-self.unary(expr, lenient)?)
+        } else if expr.accept(Token::Op('+')) {
+            self.unary(expr, lenient)
+        } else if expr.accept(Token::Op('~')) {
+            Ok(!self.unary(expr, lenient)?)
+        } else {
+            self.primary(expr, lenient)
+        }
+    }
+
+    fn primary(&self, expr: &mut Expr, lenient: bool) -> Result<i64, String> {
+        let token = match expr.tokens.get(expr.position) {
+            Some(&token) => token,
+            None => return Err(format!("bad expression '{}'", expr.text)),
+        };
+        expr.position += 1;
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Here => Ok(self.address as i64),
+            Token::Symbol(start, end) => {
+                let name = expr.text[start..end].to_uppercase();
+                match self.symbols.get(&name) {
+                    Some(&value) => Ok(value),
+                    None if lenient => Ok(0),
+                    None => Err(format!("undefined symbol {}", name)),
+                }
+            },
+            Token::Op('(') => {
+                let value = self.or(expr, lenient)?;
+                if !expr.accept(Token::Op(')')) {
+                    return Err(format!("missing ')' in '{}'", expr.text));
+                }
+                Ok(value)
+            },
+            _ => Err(format!("bad expression '{}'", expr.text)),
+        }
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[String]) -> Result<Vec<u8>, String> {
+        let (alias, translated) = translate(mnemonic, operands);
+        let (mnemonic, operands) = if alias.is_empty() { (mnemonic, operands) } else { (alias, &translated[..]) };
+        let upper: Vec<String> = operands.iter().map(|o| o.to_uppercase()).collect();
+        let keywords: Vec<&str> = upper.iter().map(|o| o.as_str()).collect();
+        let regs: Vec<Option<usize>> = operands.iter().map(|o| register(o)).collect();
+        let xy = |base: u16, x: usize, y: usize| base | (x as u16) << 8 | (y as u16) << 4;
+        let x = |base: u16, x: usize| base | (x as u16) << 8;
+        let bad = || format!("bad operands for {}: {}", mnemonic, operands.join(", "));
+
+        let opcode = match (mnemonic, &regs[..]) {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SCR", []) => 0x00FB,
+            ("SCL", []) => 0x00FC,
+            ("EXIT", []) => 0x00FD,
+            ("LOW", []) => 0x00FE,
+            ("HIGH", []) => 0x00FF,
+            ("AUDIO", []) => 0xF002,
+            ("SCD", [None]) => 0x00C0 | self.nibble(&operands[0])?,
+            ("SCU", [None]) => 0x00D0 | self.nibble(&operands[0])?,
+            ("SYS", [None]) => self.address(&operands[0])?,
+            ("JP", [None]) => 0x1000 | self.address(&operands[0])?,
+            ("JP", [Some(0), None]) => 0xB000 | self.address(&operands[1])?,
+            ("CALL", [None]) => 0x2000 | self.address(&operands[0])?,
+            ("SE", [Some(vx), Some(vy)]) => xy(0x5000, *vx, *vy),
+            ("SE", [Some(vx), None]) => x(0x3000, *vx) | self.byte(&operands[1])? as u16,
+            ("SNE", [Some(vx), Some(vy)]) => xy(0x9000, *vx, *vy),
+            ("SNE", [Some(vx), None]) => x(0x4000, *vx) | self.byte(&operands[1])? as u16,
+            ("SAVE", [Some(vx), Some(vy)]) => xy(0x5002, *vx, *vy),
+            ("LOAD", [Some(vx), Some(vy)]) => xy(0x5003, *vx, *vy),
+            ("OR", [Some(vx), Some(vy)]) => xy(0x8001, *vx, *vy),
+            ("AND", [Some(vx), Some(vy)]) => xy(0x8002, *vx, *vy),
+            ("XOR", [Some(vx), Some(vy)]) => xy(0x8003, *vx, *vy),
+            ("ADD", [Some(vx), Some(vy)]) => xy(0x8004, *vx, *vy),
+            ("SUB", [Some(vx), Some(vy)]) => xy(0x8005, *vx, *vy),
+            ("SHR", [Some(vx)]) => x(0x8006, *vx),
+            ("SHR", [Some(vx), Some(vy)]) => xy(0x8006, *vx, *vy),
+            ("SUBN", [Some(vx), Some(vy)]) => xy(0x8007, *vx, *vy),
+            ("SHL", [Some(vx)]) => x(0x800E, *vx),
+            ("SHL", [Some(vx), Some(vy)]) => xy(0x800E, *vx, *vy),
+            ("ADD", [None, Some(vx)]) if keywords[0] == "I" => x(0xF01E, *vx),
+            ("ADD", [Some(vx), None]) => x(0x7000, *vx) | self.byte(&operands[1])? as u16,
+            ("RND", [Some(vx), None]) => x(0xC000, *vx) | self.byte(&operands[1])? as u16,
+            ("DRW", [Some(vx), Some(vy), None]) => xy(0xD000, *vx, *vy) | self.nibble(&operands[2])?,
+            ("SKP", [Some(vx)]) => x(0xE09E, *vx),
+            ("SKNP", [Some(vx)]) => x(0xE0A1, *vx),
+            ("PLANE", [None]) => 0xF001 | self.nibble(&operands[0])? << 8,
+            ("PITCH", [Some(vx)]) => x(0xF03A, *vx),
+            ("LD", [Some(vx), Some(vy)]) => xy(0x8000, *vx, *vy),
+            ("LD", [Some(vx), None]) => match keywords[1] {
+                "DT" => x(0xF007, *vx),
+                "K" => x(0xF00A, *vx),
+                "[I]" => x(0xF065, *vx),
+                "R" => x(0xF085, *vx),
+                _ => x(0x6000, *vx) | self.byte(&operands[1])? as u16,
+            },
+            ("LD", [None, Some(vx)]) => match keywords[0] {
+                "DT" => x(0xF015, *vx),
+                "ST" => x(0xF018, *vx),
+                "F" => x(0xF029, *vx),
+                "HF" => x(0xF030, *vx),
+                "B" => x(0xF033, *vx),
+                "[I]" => x(0xF055, *vx),
+                "R" => x(0xF075, *vx),
+                _ => return Err(bad()),
+            },
+            ("LD", [None, None]) if keywords[0] == "I" => {
+                if keywords[1].starts_with("LONG ") {
+                    let addr = self.value(operands[1][5..].trim(), 0, 0xFFFF)?;
+                    return Ok(vec![0xF0, 0x00, (addr >> 8) as u8, addr as u8]);
+                }
+                0xA000 | self.address(&operands[1])?
+            },
+            _ if MNEMONICS.contains(&mnemonic) => return Err(bad()),
+            _ => return Err(format!("unknown instruction {}", mnemonic)),
+        };
+        Ok(vec![(opcode >> 8) as u8, opcode as u8])
+    }
+}
+
+impl<'a> Expr<'a> {
+    fn accept(&mut self, token: Token) -> bool {
+        if self.tokens.get(self.position) == Some(&token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Assembles Chipper source into a ROM that starts at 0x200, or at the first
+// ORG. VBRIX-style mnemonics (mov, jsr, skeq, ...) are accepted as well.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut first = Assembler::new(true, HashMap::new(), false);
+    first.run(source)?;
+    // Lay a Robson source out again now that its grouping is known, so that
+    // both passes agree from the first line.
+    if first.robson {
+        first = Assembler::new(true, HashMap::new(), true);
+        first.run(source)?;
+    }
+    let mut second = Assembler::new(false, first.symbols, first.robson);
+    second.run(source)?;
+    Ok(second.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+    use disasm::trace;
+
+    fn read(path: &Path) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn assembles_labels_and_expressions() {
+        let source = "
+WIDTH   EQU 8
+start:  LD   I, sprite
+        LD   V0, WIDTH * 2 + 1
+        ADD  V0, -1
+        DRW  V0, V1, 2
+        JP   start
+sprite: DB   $1..1...., #FF";
+        assert_eq!(assemble(source).unwrap(),
+                   vec![0xA2, 0x0A, 0x60, 0x11, 0x70, 0xFF, 0xD0, 0x12, 0x12, 0x00, 0x90, 0xFF]);
+    }
+    #[test]
+    fn alignment_pads_instructions() {
+        assert_eq!(assemble("DB 1\nCLS").unwrap(), vec![0x01, 0x00, 0x00, 0xE0]);
+        assert_eq!(assemble("ALIGN OFF\nDB 1\nCLS").unwrap(), vec![0x01, 0x00, 0xE0]);
+    }
+    #[test]
+    fn subtraction_groups_as_the_dialect_or_option_says() {
+        assert_eq!(assemble("DB 10-4-3").unwrap(), vec![3]);
+        assert_eq!(assemble("OPTION RIGHTTOLEFT\nDB 10-4-3").unwrap(), vec![9]);
+        // The option applies from its line on, the same in both passes.
+        let source = "DB 10-4-3, LATER\nOPTION RIGHTTOLEFT\nDB 10-4-3\nOPTION LEFTTORIGHT\nDB 10-4-3\nLATER = 8-4-2";
+        assert_eq!(assemble(source).unwrap(), vec![3, 2, 9, 3]);
+        // Sources in Robson's dialect group his way throughout, unless told
+        // otherwise.
+        assert_eq!(assemble("DB 10-4-3\nmov v0,10-4-3").unwrap(), vec![9, 0, 0x60, 0x09]);
+        assert_eq!(assemble("OPTION LEFTTORIGHT\nmov v0,10-4-3").unwrap(), vec![0x60, 0x03]);
+    }
+    #[test]
+    fn conditional_assembly() {
+        let source = "DEFINE BIG\nIFDEF BIG\nHIGH\nELSE\nLOW\nENDIF\nIFDEF SMALL\nCLS\nENDIF";
+        assert_eq!(assemble(source).unwrap(), vec![0x00, 0xFF]);
+    }
+    #[test]
+    fn reports_errors_by_line() {
+        let error = assemble("CLS\nJP nowhere").unwrap_err();
+        assert_eq!(error, AsmError { line: 2, message: "undefined symbol NOWHERE".to_string() });
+        assert!(assemble("LD V0, 256").is_err());
+    }
+    #[test]
+    fn bundled_sources_assemble_to_their_roms() {
+        let games = Path::new(env!("CARGO_MANIFEST_DIR")).join("games");
+        let mut checked = 0;
+        for entry in fs::read_dir(games.join("SOURCES")).unwrap() {
+            let path = entry.unwrap().path();
+            // The sources are DOS text; decode them byte for byte.
+            let source: String = read(&path).iter().map(|&byte| byte as char).collect();
+            let rom = read(&games.join(path.file_stem().unwrap()));
+            match assemble(&source) {
+                Ok(output) => assert!(output == rom, "{} does not match its ROM", path.display()),
+                Err(why) => panic!("{}: {}", path.display(), why),
+            }
+            checked += 1;
+        }
+        assert_eq!(checked, 10);
+    }
+    #[test]
+    fn disassembled_games_reassemble() {
+        let games = Path::new(env!("CARGO_MANIFEST_DIR")).join("games");
+        for entry in fs::read_dir(&games).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() || path.extension().is_some() { continue; }
+            let rom = read(&path);
+            let source = trace(&rom, 0x200).to_source();
+            assert_eq!(assemble(&source).unwrap(), rom, "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,53 @@
+extern crate getopts;
+extern crate rschip8;
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use rschip8::assemble;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut opts = getopts::Options::new();
+    opts.reqopt("o", "", "where to write the rom", "ROM");
+    opts.optflag("h", "help", "print this help message");
+    let brief = format!("Usage: {} SOURCE -o ROM", args[0]);
+
+    if args[1..].iter().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", opts.usage(&brief));
+        return;
+    }
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprint!("{}\n{}", f, opts.usage(&brief));
+            std::process::exit(1);
+        }
+    };
+    if matches.free.len() != 1 {
+        eprint!("{}", opts.usage(&brief));
+        std::process::exit(1);
+    }
+
+    let path = &matches.free[0];
+    let mut bytes = Vec::new();
+    if let Err(why) = File::open(path).and_then(|mut file| file.read_to_end(&mut bytes)) {
+        eprintln!("couldn't read {}: {}", path, why);
+        std::process::exit(1);
+    }
+    // Chipper sources are 8-bit DOS text, so take each byte as a character.
+    let source: String = bytes.iter().map(|&byte| byte as char).collect();
+
+    let rom = match assemble(&source) {
+        Ok(rom) => rom,
+        Err(why) => {
+            eprintln!("{}: {}", path, why);
+            std::process::exit(1);
+        }
+    };
+    let out = matches.opt_str("o").unwrap();
+    if let Err(why) = File::create(&out).and_then(|mut file| file.write_all(&rom)) {
+        eprintln!("couldn't write {}: {}", out, why);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,54 @@
+extern crate getopts;
+extern crate rschip8;
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use rschip8::{disassemble, trace};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut opts = getopts::Options::new();
+    opts.optopt("o", "origin", "load address of the first byte (hex, default 200)", "ADDR");
+    opts.optflag("s", "source", "follow the code from the entry point and print reassemblable source");
+    opts.optflag("h", "help", "print this help message");
+    let brief = format!("Usage: {} ROM [-o ADDR] [-s]", args[0]);
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprint!("{}\n{}", f, opts.usage(&brief));
+            std::process::exit(1);
+        }
+    };
+    if matches.opt_present("h") || matches.free.len() != 1 {
+        print!("{}", opts.usage(&brief));
+        return;
+    }
+
+    let origin = match matches.opt_str("o") {
+        Some(s) => match usize::from_str_radix(s.trim_start_matches("0x"), 16) {
+            Ok(origin) => origin,
+            Err(_) => {
+                eprintln!("bad origin '{}'", s);
+                std::process::exit(1);
+            }
+        },
+        None => 0x200,
+    };
+
+    let path = &matches.free[0];
+    let mut buffer = Vec::new();
+    if let Err(why) = File::open(path).and_then(|mut file| file.read_to_end(&mut buffer)) {
+        eprintln!("couldn't read {}: {}", path, why);
+        std::process::exit(1);
+    }
+
+    if matches.opt_present("s") {
+        print!("{}", trace(&buffer, origin).to_source());
+    } else {
+        for instruction in disassemble(&buffer, origin) {
+            println!("{}", instruction);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use cpu::Chip8;
+use display::Display;
+use error::Chip8Error;
+use headless_frontend::{run_headless, HeadlessFrontend, KeyPress};
+use quirks::Quirks;
+
+// A pattern a test ROM draws to report a result, one string per row with '#'
+// for lit pixels and '.' for unlit ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Glyph {
+    pub name: &'static str,
+    pub rows: &'static [&'static str],
+}
+
+// The glyphs drawn by this project's own test ROMs in tests/roms: a tick or a
+// cross for each check, and a filled or hollow dot for each quirk the
+// interpreter shows.
+pub const PASS_GLYPH: Glyph = Glyph { name: "pass", rows: &[".....",
+                                                            "....#",
+                                                            "...#.",
+                                                            "#.#..",
+                                                            ".#..."] };
+pub const FAIL_GLYPH: Glyph = Glyph { name: "fail", rows: &["#...#",
+                                                            ".#.#.",
+                                                            "..#..",
+                                                            ".#.#.",
+                                                            "#...#"] };
+pub const ON_GLYPH: Glyph = Glyph { name: "on", rows: &[".###.",
+                                                        "#####",
+                                                        "#####",
+                                                        "#####",
+                                                        ".###."] };
+pub const OFF_GLYPH: Glyph = Glyph { name: "off", rows: &[".###.",
+                                                          "#...#",
+                                                          "#...#",
+                                                          "#...#",
+                                                          ".###."] };
+pub const RESULT_GLYPHS: [Glyph; 4] = [PASS_GLYPH, FAIL_GLYPH, ON_GLYPH, OFF_GLYPH];
+
+// The marks Timendus' CHIP-8 test suite draws after each check: a tick when
+// it passed and a cross when it failed.
+pub const SUITE_PASS_GLYPH: Glyph = Glyph { name: "pass", rows: &["......#",
+                                                                  ".....#.",
+                                                                  "#...#..",
+                                                                  ".#.#...",
+                                                                  "..#...."] };
+pub const SUITE_FAIL_GLYPH: Glyph = Glyph { name: "fail", rows: &["#...#",
+                                                                  ".#.#.",
+                                                                  "..#..",
+                                                                  ".#.#.",
+                                                                  "#...#"] };
+pub const SUITE_GLYPHS: [Glyph; 2] = [SUITE_PASS_GLYPH, SUITE_FAIL_GLYPH];
+
+// The suite's menus read this byte first, and skip straight to the choice
+// found there if it is not 0.
+pub const SUITE_MENU_ADDRESS: usize = 0x1FF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlyphMatch {
+    pub name: &'static str,
+    pub x: usize,
+    pub y: usize,
+}
+
+fn matches_at(display: &Display, glyph: &Glyph, left: usize, top: usize) -> bool {
+    let height = glyph.rows.len() as isize;
+    let width = glyph.rows.iter().map(|row| row.len()).max().unwrap_or(0) as isize;
+    // The pixels just around the glyph must be dark too, so a glyph is not
+    // found inside some bigger drawing.
+    for dy in -1..height + 1 {
+        for dx in -1..width + 1 {
+            let (x, y) = (left as isize + dx, top as isize + dy);
+            if x < 0 || y < 0 || x >= display.width() as isize || y >= display.height() as isize {
+                if (0..width).contains(&dx) && (0..height).contains(&dy) { return false; }
+                continue;
+            }
+            let lit = glyph.rows.get(dy as usize)
+                .and_then(|row| row.as_bytes().get(dx as usize))
+                .is_some_and(|&pixel| pixel == b'#');
+            if display.get(x as usize, y as usize) != lit { return false; }
+        }
+    }
+    true
+}
+
+// Every place one of the glyphs is drawn, in reading order.
+pub fn find_glyphs(display: &Display, glyphs: &[Glyph]) -> Vec<GlyphMatch> {
+    let mut found = Vec::new();
+    for y in 0..display.height() {
+        for x in 0..display.width() {
+            for glyph in glyphs.iter() {
+                if matches_at(display, glyph, x, y) {
+                    found.push(GlyphMatch { name: glyph.name, x, y });
+                }
+            }
+        }
+    }
+    found
+}
+
+// The names of the result glyphs on screen, in reading order.
+pub fn read_results(display: &Display) -> Vec<&'static str> {
+    find_glyphs(display, &RESULT_GLYPHS).iter().map(|found| found.name).collect()
+}
+
+// Runs a test ROM headlessly for the given number of frames, pressing the
+// scripted keys, and returns what it left on screen. Test ROMs end in an
+// endless loop once every result is drawn, so running on is harmless.
+pub fn run_test_rom(rom: &[u8], quirks: Quirks, cycles_per_frame: usize, frames: u64,
+                    keys: Vec<KeyPress>) -> Result<Display, Chip8Error> {
+    let mut chip8 = Chip8::new(quirks);
+    chip8.load(rom.to_vec())?;
+    run_headless(&mut chip8, HeadlessFrontend::new(keys, Some(frames)), cycles_per_frame, None)?;
+    Ok(chip8.display().clone())
+}
+
+// Runs a ROM from Timendus' suite like run_test_rom, answering its menu, if it
+// has one, with menu_choice.
+pub fn run_suite_rom(rom: &[u8], quirks: Quirks, cycles_per_frame: usize, frames: u64,
+                     menu_choice: Option<u8>) -> Result<Display, Chip8Error> {
+    let mut chip8 = Chip8::new(quirks);
+    chip8.load(rom.to_vec())?;
+    if let Some(choice) = menu_choice {
+        chip8.memory_mut()[SUITE_MENU_ADDRESS] = choice;
+    }
+    run_headless(&mut chip8, HeadlessFrontend::new(Vec::new(), Some(frames)), cycles_per_frame, None)?;
+    Ok(chip8.display().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(display: &mut Display, glyph: &Glyph, left: usize, top: usize) {
+        for (y, row) in glyph.rows.iter().enumerate() {
+            for (x, pixel) in row.bytes().enumerate() {
+                if pixel == b'#' { display.flip(left + x, top + y, 1); }
+            }
+        }
+    }
+
+    #[test]
+    fn reads_glyphs_in_order() {
+        let mut display = Display::new(64, 32);
+        draw(&mut display, &FAIL_GLYPH, 10, 9);
+        draw(&mut display, &PASS_GLYPH, 2, 2);
+        draw(&mut display, &ON_GLYPH, 2, 9);
+        draw(&mut display, &OFF_GLYPH, 40, 20);
+        // A stray pixel next to a glyph hides it.
+        display.flip(45, 22, 1);
+        assert_eq!(read_results(&display), vec!["pass", "on", "fail"]);
+        assert_eq!(find_glyphs(&display, &[FAIL_GLYPH]), vec![GlyphMatch { name: "fail", x: 10, y: 9 }]);
+    }
+    #[test]
+    fn suite_menu_choice_is_poked_before_the_rom_runs() {
+        // LD I, 0x1FF; LD V0, [I]; LD F, V0; DRW V1, V1, 5; JP 0x208
+        let rom = [0xA1, 0xFF, 0xF0, 0x65, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x08];
+        let display = run_suite_rom(&rom, Quirks::default(), 10, 2, Some(1)).unwrap();
+        // The top row of the font's 1 lights only its third pixel; 0 lights all four.
+        assert!(!display.get(0, 0) && display.get(2, 0));
+    }
+}
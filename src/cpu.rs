@@ -1,5 +1,16 @@
-use rand::random;
-use {DISPWIDTH, DISPSIZE, MEMSIZE, STACKSIZE, NUM_KEYS, NUM_REGS};
+use error::Chip8Error;
+use quirks::Quirks;
+use sound::{SoundEvent, Tone};
+use std::io;
+use std::path::Path;
+
+use display::Display;
+use rng::Rng;
+use screenshot::{self, ImageFormat};
+use state::{StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
+use {DISPWIDTH, DISPHEIGHT, HIRES_WIDTH, HIRES_HEIGHT, MEMSIZE, STACKSIZE, NUM_KEYS, NUM_REGS};
+
+const PROGRAM_START: usize = 0x200;
 
 const FONTSET: [u8; 80] =
 [ 
@@ -21,8 +32,31 @@ const FONTSET: [u8; 80] =
   0xF0, 0x80, 0xF0, 0x80, 0x80  // F
 ];
 
-#[derive(Debug, PartialEq)]
-enum Chip8Op {
+// SUPER-CHIP 8x10 digits, stored right after the small font.
+const BIG_FONTSET_START: usize = 0x50;
+const BIG_FONTSET: [u8; 160] =
+[
+  0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+  0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+  0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+  0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+  0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+  0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+  0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+  0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+  0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+  0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+  0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+  0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0  // F
+];
+
+/// A decoded instruction, with its operands pulled out of the opcode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chip8Op {
     DisplayClear,
     Return,
     Jump(usize),
@@ -50,13 +84,29 @@ enum Chip8Op {
     KeyNotPressed(usize),
     GetDelay(usize),
     GetKey(usize),
-    SetDelay(u8),
-    SetSound(u8),
+    SetDelay(usize),
+    SetSound(usize),
     AddMemoryAddress(usize),
     GetSprite(usize),
     BinaryCoding(usize),
     RegisterDump(usize),
     RegisterLoad(usize),
+    ScrollDown(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowRes,
+    HighRes,
+    GetBigSprite(usize),
+    SaveFlags(usize),
+    LoadFlags(usize),
+    ScrollUp(u8),
+    LongSetMemoryAddress,
+    SaveRange(usize, usize),
+    LoadRange(usize, usize),
+    SelectPlanes(u8),
+    LoadAudio,
+    SetPitch(usize),
 }
 
 fn x_nibble(opcode: u16) -> u8 {
@@ -93,18 +143,35 @@ fn aXbb(opcode: u16, a: u8, b: u8) -> bool {
     first_nibble(opcode) == a && nn_byte(opcode) == b
 }
 
-fn decode_opcode(opcode: u16) -> Chip8Op {
+// XO-CHIP register ranges run from VX to VY in either direction.
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    if x <= y { Box::new(x..y + 1) } else { Box::new((y..x + 1).rev()) }
+}
+
+/// Returns None for opcodes no supported platform defines.
+pub fn decode_opcode(opcode: u16) -> Option<Chip8Op> {
     let x = x_nibble(opcode) as usize;
     let y = y_nibble(opcode) as usize;
     let word = nnn_word(opcode) as usize;
-    match opcode {
+    let op = match opcode {
         0x00E0 => Chip8Op::DisplayClear,
         0x00EE => Chip8Op::Return,
+        0x00FB => Chip8Op::ScrollRight,
+        0x00FC => Chip8Op::ScrollLeft,
+        0x00FD => Chip8Op::Exit,
+        0x00FE => Chip8Op::LowRes,
+        0x00FF => Chip8Op::HighRes,
+        o if o & 0xFFF0 == 0x00C0 => Chip8Op::ScrollDown(last_nibble(o)),
+        o if o & 0xFFF0 == 0x00D0 => Chip8Op::ScrollUp(last_nibble(o)),
+        0xF000 => Chip8Op::LongSetMemoryAddress,
+        0xF002 => Chip8Op::LoadAudio,
         o if first_nibble(o) == 0x1 => Chip8Op::Jump(word),
         o if first_nibble(o) == 0x2 => Chip8Op::Call(word),
         o if first_nibble(o) == 0x3 => Chip8Op::CmpEqualConst(x, nn_byte(o)),
         o if first_nibble(o) == 0x4 => Chip8Op::CmpNotEqualConst(x, nn_byte(o)),
         o if aXYb(o, 0x5, 0x0) => Chip8Op::CmpEqualReg(x, y),
+        o if aXYb(o, 0x5, 0x2) => Chip8Op::SaveRange(x, y),
+        o if aXYb(o, 0x5, 0x3) => Chip8Op::LoadRange(x, y),
         o if first_nibble(o) == 0x6 => Chip8Op::SetRegConst(x, nn_byte(o)),
         o if first_nibble(o) == 0x7 => Chip8Op::AddConstReg(x, nn_byte(o)),
         o if aXYb(o, 0x8, 0x0) => Chip8Op::SetRegReg(x, y),
@@ -123,128 +190,558 @@ fn decode_opcode(opcode: u16) -> Chip8Op {
         o if first_nibble(o) == 0xD => Chip8Op::DrawSprite(x, y, last_nibble(o)),
         o if aXbb(o, 0xE, 0x9E) => Chip8Op::KeyPressed(x),
         o if aXbb(o, 0xE, 0xA1) => Chip8Op::KeyNotPressed(x),
+        o if aXbb(o, 0xF, 0x01) => Chip8Op::SelectPlanes(x as u8),
         o if aXbb(o, 0xF, 0x07) => Chip8Op::GetDelay(x),
         o if aXbb(o, 0xF, 0x0A) => Chip8Op::GetKey(x),
-        o if aXbb(o, 0xF, 0x15) => Chip8Op::SetDelay(x as u8),
-        o if aXbb(o, 0xF, 0x18) => Chip8Op::SetSound(x as u8),
+        o if aXbb(o, 0xF, 0x15) => Chip8Op::SetDelay(x),
+        o if aXbb(o, 0xF, 0x18) => Chip8Op::SetSound(x),
         o if aXbb(o, 0xF, 0x1E) => Chip8Op::AddMemoryAddress(x),
         o if aXbb(o, 0xF, 0x29) => Chip8Op::GetSprite(x),
+        o if aXbb(o, 0xF, 0x30) => Chip8Op::GetBigSprite(x),
         o if aXbb(o, 0xF, 0x33) => Chip8Op::BinaryCoding(x),
+        o if aXbb(o, 0xF, 0x3A) => Chip8Op::SetPitch(x),
         o if aXbb(o, 0xF, 0x55) => Chip8Op::RegisterDump(x),
         o if aXbb(o, 0xF, 0x65) => Chip8Op::RegisterLoad(x),
-        _ => panic!(format!("invalid opcode {:x}", opcode)),
-    }
+        o if aXbb(o, 0xF, 0x75) => Chip8Op::SaveFlags(x),
+        o if aXbb(o, 0xF, 0x85) => Chip8Op::LoadFlags(x),
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
 }
 
+/// A data access made by an instruction. Instruction fetches are not included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryAccess {
+    pub address: usize,
+    pub kind: AccessKind,
+    pub value: u8,
+}
+
+/// The CPU registers and stack as plain values, for embedders to snapshot,
+/// compare and restore without a whole save state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuState {
+    pub registers: [u8; NUM_REGS],
+    /// The I register.
+    pub index: usize,
+    pub pc: usize,
+    pub sp: usize,
+    /// Only the first sp entries are in use.
+    pub stack: [usize; STACKSIZE],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+/// A whole machine: memory, registers, timers, keys and screen, run with a
+/// given set of quirks. Build one with new, load a ROM and call run_frame
+/// once per 60 Hz frame; see the crate documentation for a full loop.
 pub struct Chip8 {
-    memory: [u8; MEMSIZE],
+    memory: Vec<u8>,
     registers: [u8; NUM_REGS],
     stack: [usize; STACKSIZE],
     delay_timer: u8,
     sound_timer: u8,
     keys: [bool; NUM_KEYS],
-    pub display: [bool; DISPSIZE],
-    pub draw_flag: bool,
-    pub trace_flag: bool,
-    pub beep_flag: bool,
-    pub wait_for_key_flag: bool,
+    display: Display,
+    draw_flag: bool,
+    exit_flag: bool,
+    trace_flag: bool,
+    wait_for_key_flag: bool,
     key_target: usize,
     pc: usize, // program counter
     sp: usize, // stack pointer
     ma: usize, // memory address
+    quirks: Quirks,
+    frame_drawn: bool,
+    rpl_flags: [u8; NUM_REGS],
+    planes: u8,
+    audio_pattern: Option<[u8; 16]>,
+    pitch: u8,
+    reported_tone: Option<Tone>,
+    accesses: Vec<MemoryAccess>,
+    rng: Rng,
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new(Quirks::default())
+    }
 }
 
 impl Chip8 {
-    pub fn new() -> Self {
-        let mut this_memory = [0; MEMSIZE];
-        for i in 0..80 {
-            this_memory[i] = FONTSET[i];
-        }
+    /// A machine with the fonts in place and nothing loaded.
+    pub fn new(quirks: Quirks) -> Self {
+        let mut this_memory = vec![0; MEMSIZE];
+        this_memory[..FONTSET.len()].copy_from_slice(&FONTSET);
+        this_memory[BIG_FONTSET_START..BIG_FONTSET_START + BIG_FONTSET.len()].copy_from_slice(&BIG_FONTSET);
         Chip8 {memory: this_memory,
                 registers: [0; NUM_REGS],
                 stack: [0; STACKSIZE],
                 delay_timer: 0,
                 sound_timer: 0,
                 keys: [false; NUM_KEYS],
-                display: [false; DISPSIZE],
+                display: Display::new(DISPWIDTH, DISPHEIGHT),
                 draw_flag: false,
+                exit_flag: false,
                 trace_flag: false,
-                beep_flag: false,
                 wait_for_key_flag: false,
                 key_target: 0,
-                pc: 0x200,
+                pc: PROGRAM_START,
                 sp: 0,
                 ma: 0,
+                quirks,
+                frame_drawn: false,
+                rpl_flags: [0; NUM_REGS],
+                planes: 0x1,
+                audio_pattern: None,
+                pitch: 64,
+                reported_tone: None,
+                accesses: Vec::new(),
+                rng: Rng::default(),
             }
     }
-    pub fn load(&mut self, buffer: Vec<u8>) {
-        for (index, byte) in buffer.iter().enumerate() {
-            self.memory[0x200 + index] = *byte;
+    /// Copies a ROM to 0x200, where programs start.
+    pub fn load(&mut self, buffer: Vec<u8>) -> Result<(), Chip8Error> {
+        if buffer.len() > MEMSIZE - PROGRAM_START {
+            return Err(Chip8Error::RomTooLarge { size: buffer.len() });
         }
+        self.memory[PROGRAM_START..PROGRAM_START + buffer.len()].copy_from_slice(&buffer);
+        Ok(())
     }
-    pub fn emulate_cycle(&mut self) {
-        self.draw_flag = false;
-
-        let opcode = self.fetch_opcode();
-        let op = decode_opcode(opcode);
-        self.execute_op(op);
-
+    /// On error the program counter is left past the offending instruction,
+    /// so calling emulate_cycle again skips it. draw_flag stays set until the
+    /// caller clears it, so draws are not lost when several cycles run per frame.
+    pub fn emulate_cycle(&mut self) -> Result<(), Chip8Error> {
+        self.accesses.clear();
+        let address = self.pc;
+        let opcode = self.fetch_opcode()?;
+        let op = match decode_opcode(opcode) {
+            Some(op) => op,
+            None => return Err(Chip8Error::InvalidOpcode { opcode, address }),
+        };
+        self.execute_op(op, address)
+    }
+    /// The delay and sound timers count down at 60 Hz regardless of how many
+    /// instructions run in between; call this once per frame.
+    pub fn tick_timers(&mut self) {
+        self.frame_drawn = false;
         if self.delay_timer > 0 { self.delay_timer -= 1; }
-        if self.sound_timer > 0 {
-            self.sound_timer -= 1;
-            if self.sound_timer == 0 { 
-                self.beep_flag = true; 
-            }
+        if self.sound_timer > 0 { self.sound_timer -= 1; }
+    }
+    /// The buzzer sounds for as long as the sound timer is non-zero.
+    pub fn buzzer_on(&self) -> bool {
+        self.sound_timer > 0
+    }
+    /// The XO-CHIP audio pattern loaded by F002, if any. While the sound timer
+    /// runs it should play in a loop, one bit per sample, at audio_sample_rate.
+    pub fn audio_pattern(&self) -> Option<[u8; 16]> {
+        self.audio_pattern
+    }
+    pub fn audio_sample_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+    pub fn tone(&self) -> Tone {
+        match self.audio_pattern {
+            Some(bits) => Tone::Pattern { bits, sample_rate: self.audio_sample_rate() },
+            None => Tone::Buzzer,
         }
     }
+    /// Reports the buzzer turning on or off, or the tone changing while it is
+    /// on, since the last call. Poll once per frame after tick_timers.
+    pub fn poll_sound_event(&mut self) -> Option<SoundEvent> {
+        let current = if self.buzzer_on() { Some(self.tone()) } else { None };
+        if current == self.reported_tone { return None; }
+        self.reported_tone = current;
+        match current {
+            Some(tone) => Some(SoundEvent::Start(tone)),
+            None => Some(SoundEvent::Stop),
+        }
+    }
+    /// Presses are latched until an EX9E or EXA1 looks at the key, so a tap
+    /// between two checks is not lost. They do not answer FX0A; use give_key.
     pub fn set_keys(&mut self, keys_pressed: [bool; 16]) {
-        for i in 0..NUM_KEYS {
-            self.keys[i] = self.keys[i] || keys_pressed[i];
+        for (key, &pressed) in self.keys.iter_mut().zip(keys_pressed.iter()) {
+            *key = *key || pressed;
         }
     }
+    /// Answers FX0A with a key and lets the program carry on.
     pub fn give_key(&mut self, key: u8) {
         self.registers[self.key_target] = key;
+        self.wait_for_key_flag = false;
+    }
+    /// The register an FX0A is waiting to fill, while it waits.
+    pub fn waiting_for_key(&self) -> Option<usize> {
+        if self.wait_for_key_flag { Some(self.key_target) } else { None }
+    }
+    /// Set once the program runs 00FD; it stays on that instruction.
+    pub fn exited(&self) -> bool {
+        self.exit_flag
+    }
+    /// Stops the program as 00FD would.
+    pub fn exit(&mut self) {
+        self.exit_flag = true;
+    }
+    /// The screen as it is now.
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+    /// Whether the screen has changed since the last call.
+    pub fn take_draw_flag(&mut self) -> bool {
+        let drawn = self.draw_flag;
+        self.draw_flag = false;
+        drawn
     }
-    fn fetch_opcode(&mut self) -> u16 {
+    /// Prints every instruction and the registers around it to stdout.
+    pub fn set_trace(&mut self, on: bool) {
+        self.trace_flag = on;
+    }
+    pub fn tracing(&self) -> bool {
+        self.trace_flag
+    }
+    /// Runs one instruction, unless the program has exited or is waiting on
+    /// FX0A, in which case nothing happens.
+    pub fn step(&mut self) -> Result<(), Chip8Error> {
+        if self.exit_flag || self.wait_for_key_flag { return Ok(()); }
+        self.emulate_cycle()
+    }
+    /// Runs one 60 Hz frame: up to cycles instructions, then the timers. The
+    /// frame's instructions are cut short if the program exits or waits on
+    /// FX0A. Set the keys before and check the screen after.
+    pub fn run_frame(&mut self, cycles: usize) -> Result<(), Chip8Error> {
+        for _ in 0..cycles {
+            if self.exit_flag || self.wait_for_key_flag { break; }
+            self.emulate_cycle()?;
+        }
+        self.tick_timers();
+        Ok(())
+    }
+    /// Steps until done returns true after an instruction, the program exits
+    /// or waits on FX0A, or max_steps instructions have run. Timers do not
+    /// tick. Returns the number of instructions run.
+    pub fn run_until<P>(&mut self, max_steps: usize, mut done: P) -> Result<usize, Chip8Error>
+        where P: FnMut(&Chip8) -> bool {
+        for steps in 0..max_steps {
+            if self.exit_flag || self.wait_for_key_flag { return Ok(steps); }
+            self.emulate_cycle()?;
+            if done(self) { return Ok(steps + 1); }
+        }
+        Ok(max_steps)
+    }
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+    pub fn registers(&self) -> [u8; NUM_REGS] {
+        self.registers
+    }
+    /// The I register.
+    pub fn index(&self) -> usize {
+        self.ma
+    }
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+    /// Return addresses currently on the stack, oldest first.
+    pub fn stack(&self) -> &[usize] {
+        &self.stack[..self.sp]
+    }
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+    pub fn keys(&self) -> [bool; NUM_KEYS] {
+        self.keys
+    }
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+    /// Memory can be patched freely; the program sees the change on its next
+    /// fetch.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+    /// The registers, stack and timers, to be put back with set_cpu_state.
+    pub fn cpu_state(&self) -> CpuState {
+        CpuState { registers: self.registers,
+                   index: self.ma,
+                   pc: self.pc,
+                   sp: self.sp,
+                   stack: self.stack,
+                   delay_timer: self.delay_timer,
+                   sound_timer: self.sound_timer,
+        }
+    }
+    /// Values out of range are masked or clamped as by the setters below.
+    pub fn set_cpu_state(&mut self, state: &CpuState) {
+        self.registers = state.registers;
+        self.stack = state.stack;
+        self.set_index(state.index);
+        self.set_pc(state.pc);
+        self.set_sp(state.sp);
+        self.delay_timer = state.delay_timer;
+        self.sound_timer = state.sound_timer;
+    }
+    /// Values are masked or clamped to what the hardware could hold, so any
+    /// value is safe to set.
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.registers[x & 0xF] = value;
+    }
+    pub fn set_index(&mut self, value: usize) {
+        self.ma = value & 0xFFFF;
+    }
+    pub fn set_pc(&mut self, value: usize) {
+        self.pc = value & 0xFFFF;
+    }
+    pub fn set_sp(&mut self, value: usize) {
+        self.sp = value.min(STACKSIZE);
+    }
+    /// Replaces the return addresses, oldest first; any past the sixteenth
+    /// are dropped.
+    pub fn set_stack(&mut self, addrs: &[usize]) {
+        let len = addrs.len().min(STACKSIZE);
+        for (slot, &addr) in self.stack.iter_mut().zip(addrs[..len].iter()) {
+            *slot = addr & 0xFFFF;
+        }
+        self.sp = len;
+    }
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+    /// Memory reads and writes made by the last instruction executed.
+    pub fn memory_accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
+    }
+    /// Writes the display as it is now to an image file.
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P, format: ImageFormat) -> io::Result<()> {
+        screenshot::save_image(&self.display, format, path)
+    }
+    /// Restarts the random number generator, so that the same seed and the
+    /// same input replay a session exactly.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+    /// Snapshots the whole machine, including the quirks it runs with. The
+    /// draw and trace flags are for the front end and are not saved.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.header(STATE_MAGIC, STATE_VERSION);
+        state.u32(self.memory.len() as u32);
+        state.bytes(&self.memory);
+        state.bytes(&self.registers);
+        for &address in self.stack.iter() {
+            state.u16(address as u16);
+        }
+        state.u8(self.sp as u8);
+        state.u16(self.pc as u16);
+        state.u16(self.ma as u16);
+        state.u8(self.delay_timer);
+        state.u8(self.sound_timer);
+        for &key in self.keys.iter() {
+            state.bool(key);
+        }
+        state.u16(self.display.width() as u16);
+        state.u16(self.display.height() as u16);
+        state.bytes(self.display.pixels());
+        state.bool(self.exit_flag);
+        state.bool(self.wait_for_key_flag);
+        state.u8(self.key_target as u8);
+        state.quirks(&self.quirks);
+        state.bool(self.frame_drawn);
+        state.bytes(&self.rpl_flags);
+        state.u8(self.planes);
+        match self.audio_pattern {
+            Some(bits) => {
+                state.bool(true);
+                state.bytes(&bits);
+            },
+            None => state.bool(false),
+        }
+        state.u8(self.pitch);
+        state.u64(self.rng.state());
+        state.finish()
+    }
+    /// Restores a snapshot taken by save_state. The machine is left untouched
+    /// if the state is malformed or from an incompatible version.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), Chip8Error> {
+        let mut state = StateReader::new(bytes, |reason| Chip8Error::BadState { reason });
+        state.header(STATE_MAGIC, STATE_VERSION)?;
+        if state.u32()? as usize != MEMSIZE {
+            return Err(Chip8Error::BadState { reason: "memory size does not match" });
+        }
+        let memory = state.bytes(MEMSIZE)?.to_vec();
+        let registers: [u8; NUM_REGS] = state.array()?;
+        let mut stack = [0; STACKSIZE];
+        for address in stack.iter_mut() {
+            *address = state.u16()? as usize;
+        }
+        let sp = state.u8()? as usize;
+        if sp > STACKSIZE {
+            return Err(Chip8Error::BadState { reason: "stack pointer out of range" });
+        }
+        let pc = state.u16()? as usize;
+        let ma = state.u16()? as usize;
+        let delay_timer = state.u8()?;
+        let sound_timer = state.u8()?;
+        let mut keys = [false; NUM_KEYS];
+        for key in keys.iter_mut() {
+            *key = state.bool()?;
+        }
+        let width = state.u16()? as usize;
+        let height = state.u16()? as usize;
+        if (width, height) != (DISPWIDTH, DISPHEIGHT) && (width, height) != (HIRES_WIDTH, HIRES_HEIGHT) {
+            return Err(Chip8Error::BadState { reason: "unknown display size" });
+        }
+        let pixels = state.bytes(width * height)?.to_vec();
+        let display = match Display::from_pixels(width, height, pixels) {
+            Some(display) => display,
+            None => return Err(Chip8Error::BadState { reason: "display is malformed" }),
+        };
+        let exit_flag = state.bool()?;
+        let wait_for_key_flag = state.bool()?;
+        let key_target = state.u8()? as usize;
+        if key_target >= NUM_REGS {
+            return Err(Chip8Error::BadState { reason: "key target out of range" });
+        }
+        let quirks = state.quirks()?;
+        let frame_drawn = state.bool()?;
+        let rpl_flags: [u8; NUM_REGS] = state.array()?;
+        let planes = state.u8()?;
+        if planes > 0x3 {
+            return Err(Chip8Error::BadState { reason: "plane selection out of range" });
+        }
+        let audio_pattern = if state.bool()? { Some(state.array()?) } else { None };
+        let pitch = state.u8()?;
+        let rng = match Rng::from_state(state.u64()?) {
+            Some(rng) => rng,
+            None => return Err(Chip8Error::BadState { reason: "random number generator is stuck" }),
+        };
+        state.finish()?;
+
+        self.memory = memory;
+        self.registers = registers;
+        self.stack = stack;
+        self.sp = sp;
+        self.pc = pc;
+        self.ma = ma;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.keys = keys;
+        self.display = display;
+        // Whatever is on screen now is stale.
+        self.draw_flag = true;
+        self.exit_flag = exit_flag;
+        self.wait_for_key_flag = wait_for_key_flag;
+        self.key_target = key_target;
+        self.quirks = quirks;
+        self.frame_drawn = frame_drawn;
+        self.rpl_flags = rpl_flags;
+        self.planes = planes;
+        self.audio_pattern = audio_pattern;
+        self.pitch = pitch;
+        self.rng = rng;
+        self.accesses.clear();
+        Ok(())
+    }
+    // Decodes the instruction at pc without executing it.
+    pub(crate) fn peek_op(&self) -> Option<Chip8Op> {
+        let hi = *self.memory.get(self.pc)?;
+        let lo = *self.memory.get(self.pc + 1)?;
+        decode_opcode(((hi as u16) << 8) | lo as u16)
+    }
+    fn fetch_opcode(&mut self) -> Result<u16, Chip8Error> {
+        if self.pc + 1 >= MEMSIZE {
+            return Err(Chip8Error::PcOutOfBounds { pc: self.pc });
+        }
         let opcode = ((self.memory[self.pc] as u16) << 8) | (self.memory[self.pc + 1] as u16);
-        self.pc += 2;
-        return opcode;
+        self.pc = (self.pc + 2) & 0xFFFF;
+        Ok(opcode)
+    }
+    // Skips the next instruction, which is four bytes long if it is F000 NNNN.
+    // Like I, pc is 16 bits wide and wraps past the end of memory.
+    fn skip(&mut self) {
+        let long = self.memory.get(self.pc) == Some(&0xF0) && self.memory.get(self.pc + 1) == Some(&0x00);
+        self.pc = (self.pc + if long { 4 } else { 2 }) & 0xFFFF;
+    }
+    // All data reads and writes by instructions go through read_mem and
+    // write_mem, which record them for memory_accesses.
+    fn read_mem(&mut self, addr: usize) -> Result<u8, Chip8Error> {
+        match self.memory.get(addr) {
+            Some(&value) => {
+                self.accesses.push(MemoryAccess { address: addr, kind: AccessKind::Read, value });
+                Ok(value)
+            },
+            None => Err(Chip8Error::MemoryOutOfBounds { address: addr }),
+        }
+    }
+    fn write_mem(&mut self, addr: usize, value: u8) -> Result<(), Chip8Error> {
+        match self.memory.get_mut(addr) {
+            Some(byte) => {
+                *byte = value;
+                self.accesses.push(MemoryAccess { address: addr, kind: AccessKind::Write, value });
+                Ok(())
+            },
+            None => Err(Chip8Error::MemoryOutOfBounds { address: addr }),
+        }
+    }
+    // The operand word of F000 NNNN is part of the instruction, not data.
+    fn fetch_operand(&mut self) -> Result<usize, Chip8Error> {
+        match (self.memory.get(self.pc), self.memory.get(self.pc + 1)) {
+            (Some(&hi), Some(&lo)) => {
+                self.pc = (self.pc + 2) & 0xFFFF;
+                Ok(((hi as usize) << 8) | lo as usize)
+            },
+            _ => Err(Chip8Error::PcOutOfBounds { pc: self.pc }),
+        }
     }
     fn no_advance(&mut self) {
-        self.pc -= 2;
+        self.pc = self.pc.wrapping_sub(2) & 0xFFFF;
     }
-    fn execute_op(&mut self, op: Chip8Op) {
+    fn execute_op(&mut self, op: Chip8Op, address: usize) -> Result<(), Chip8Error> {
         if self.trace_flag { print!("{:?}\n\r", op) };
         if self.trace_flag { print!("\tMemory before: {:?}\n\r", self.ma) };
         if self.trace_flag { print!("\tRegisters before: {:?}\n\r", self.registers) };
         match op {
             Chip8Op::DisplayClear => {
-                self.display = [false; DISPSIZE];
+                self.display.clear(self.planes);
                 self.draw_flag = true;
             },
             Chip8Op::Return => {
-                self.no_advance();
-                self.pc = self.stack[self.sp];
+                if self.sp == 0 {
+                    return Err(Chip8Error::StackUnderflow { address });
+                }
                 self.sp -= 1;
+                self.pc = self.stack[self.sp];
             },
             Chip8Op::Jump(addr) => {
                 self.no_advance();
                 self.pc = addr;
             },
             Chip8Op::Call(addr) => {
-                self.no_advance();
+                if self.sp == STACKSIZE {
+                    return Err(Chip8Error::StackOverflow { address });
+                }
+                self.stack[self.sp] = self.pc;
                 self.sp += 1;
-                self.stack[self.sp] = self.pc + 2;
                 self.pc = addr;
             },
             Chip8Op::CmpEqualConst(x, c) => {
-                if self.registers[x] == c { self.pc += 2; }
+                if self.registers[x] == c { self.skip(); }
             },
             Chip8Op::CmpNotEqualConst(x, c) => {
-                if self.registers[x] != c { self.pc += 2; }
+                if self.registers[x] != c { self.skip(); }
             },
             Chip8Op::CmpEqualReg(x, y) => {
-                if self.registers[x] == self.registers[y] { self.pc += 2; }
+                if self.registers[x] == self.registers[y] { self.skip(); }
             },
             Chip8Op::SetRegConst(x, c) => {
                 self.registers[x] = c;
@@ -256,13 +753,16 @@ impl Chip8 {
                 self.registers[x] = self.registers[y];
             },
             Chip8Op::BitOpOr(x, y) => {
-                self.registers[x] = self.registers[x] | self.registers[y];
+                self.registers[x] |= self.registers[y];
+                if self.quirks.vf_reset { self.registers[0xF] = 0x0; }
             },
             Chip8Op::BitOpAnd(x, y) => {
-                self.registers[x] = self.registers[x] & self.registers[y];
+                self.registers[x] &= self.registers[y];
+                if self.quirks.vf_reset { self.registers[0xF] = 0x0; }
             },
             Chip8Op::BitOpXor(x, y) => {
-                self.registers[x] = self.registers[x] ^ self.registers[y];
+                self.registers[x] ^= self.registers[y];
+                if self.quirks.vf_reset { self.registers[0xF] = 0x0; }
             },
             Chip8Op::MathOpAdd(x, y) => {
                 let (result, overflow) = self.registers[x].overflowing_add(self.registers[y]);
@@ -275,10 +775,9 @@ impl Chip8 {
                 self.registers[0xF] = if underflow { 0x0 } else { 0x1 };
             },
             Chip8Op::BitOpShiftRight(x, y) => {
-                let lsb = self.registers[y] & 0x01;
-                self.registers[x] = self.registers[y] >> 1;
-                self.registers[y] = self.registers[y] >> 1;
-                self.registers[0x0F] = lsb;
+                let source = if self.quirks.shift_uses_vx { self.registers[x] } else { self.registers[y] };
+                self.registers[x] = source >> 1;
+                self.registers[0x0F] = source & 0x01;
             },
             Chip8Op::MathOpSubNeg(x, y) => {
                 let (result, underflow) = self.registers[y].overflowing_sub(self.registers[x]);
@@ -286,38 +785,68 @@ impl Chip8 {
                 self.registers[0xF] = if underflow { 0x0 } else { 0x1 };
             },
             Chip8Op::BitOpShiftLeft(x, y) => {
-                let msb = self.registers[y] & 0x80;
-                self.registers[x] = self.registers[y] << 1;
-                self.registers[y] = self.registers[y] << 1;
-                self.registers[0x0F] = msb;
+                let source = if self.quirks.shift_uses_vx { self.registers[x] } else { self.registers[y] };
+                self.registers[x] = source << 1;
+                self.registers[0x0F] = source >> 7;
             },
             Chip8Op::CmpNotEqualReg(x, y) => {
-                if self.registers[x] != self.registers[y] { self.pc += 2; }
+                if self.registers[x] != self.registers[y] { self.skip(); }
             },
             Chip8Op::SetMemoryAddress(addr) => {
                 self.ma = addr;
             },
             Chip8Op::JumpPlus(addr) => {
+                let offset_reg = if self.quirks.jump_uses_vx { (addr >> 8) & 0xF } else { 0x0 };
                 self.no_advance();
-                self.pc = addr + self.registers[0x00] as usize;
+                self.pc = addr + self.registers[offset_reg] as usize;
             },
             Chip8Op::Random(x, mask) => {
-                self.registers[x] = random::<u8>() & mask;
+                self.registers[x] = self.rng.next_u8() & mask;
             },
             Chip8Op::DrawSprite(x, y, h) => {
+                if self.quirks.display_wait && self.frame_drawn {
+                    // Spin on this instruction until the next frame starts.
+                    self.no_advance();
+                    return Ok(());
+                }
                 self.draw_flag = true;
-                let left = self.registers[x] as usize;
-                let top = self.registers[y] as usize;
+                self.frame_drawn = true;
+                // DXY0 draws a 16x16 sprite stored as two bytes per row.
+                let (rows, bytes_per_row) = if h == 0 { (16, 2) } else { (h as usize, 1) };
+                let width = self.display.width();
+                let height = self.display.height();
+                // The starting position always wraps; the sprite itself is
+                // clipped or wrapped at the edges depending on the quirks.
+                let left = self.registers[x] as usize % width;
+                let top = self.registers[y] as usize % height;
                 let mut collision = false;
-                for row in 0..(h as usize) {
-                    let sprite_row = self.memory[self.ma + row];
-                    for offset in 0..8 {
-                        let sprite_bit = ((0x80 >> offset) & sprite_row) > 0;
-                        let pixel_index = (top + row) * DISPWIDTH + (left + offset);
-                        let pixel_val = self.display[pixel_index % DISPSIZE];
-                        if pixel_val && sprite_bit { collision = true; }
-                        self.display[pixel_index % DISPSIZE] = sprite_bit ^ pixel_val;
+                // Each selected plane takes the next sprite's worth of data.
+                let mut sprite_addr = self.ma;
+                for &plane in [0x1, 0x2].iter() {
+                    if self.planes & plane == 0 { continue; }
+                    for row in 0..rows {
+                        let mut py = top + row;
+                        if py >= height {
+                            if self.quirks.clip_sprites { break; }
+                            py %= height;
+                        }
+                        let mut sprite_row = 0u16;
+                        for byte in 0..bytes_per_row {
+                            let addr = sprite_addr + row * bytes_per_row + byte;
+                            sprite_row = (sprite_row << 8) | self.read_mem(addr)? as u16;
+                        }
+                        let sprite_width = 8 * bytes_per_row;
+                        for offset in 0..sprite_width {
+                            let mut px = left + offset;
+                            if px >= width {
+                                if self.quirks.clip_sprites { break; }
+                                px %= width;
+                            }
+                            let sprite_bit = (sprite_row >> (sprite_width - 1 - offset)) & 0x1 == 0x1;
+                            if sprite_bit && self.display.flip(px, py, plane) { collision = true; }
+                        }
                     }
+                    sprite_addr += rows * bytes_per_row;
                 }
                 if collision {
                     self.registers[0xF] = 0x1;
@@ -325,13 +854,16 @@ impl Chip8 {
                     self.registers[0xF] = 0x0;
                 }
             },
+            // Only the low nibble of VX picks the key, as on the VIP.
             Chip8Op::KeyPressed(x) => {
-                if self.keys[self.registers[x] as usize] { self.pc += 2; }
-                self.keys[self.registers[x] as usize] = false;
+                let key = (self.registers[x] & 0xF) as usize;
+                if self.keys[key] { self.skip(); }
+                self.keys[key] = false;
             },
             Chip8Op::KeyNotPressed(x) => {
-                if !self.keys[self.registers[x] as usize] { self.pc += 2; }
-                self.keys[self.registers[x] as usize] = false;
+                let key = (self.registers[x] & 0xF) as usize;
+                if !self.keys[key] { self.skip(); }
+                self.keys[key] = false;
             },
             Chip8Op::GetDelay(x) => {
                 self.registers[x] = self.delay_timer;
@@ -340,38 +872,183 @@ impl Chip8 {
                 self.wait_for_key_flag = true;
                 self.key_target = x;
             },
-            Chip8Op::SetDelay(c) => {
-                self.delay_timer = c;
+            Chip8Op::SetDelay(x) => {
+                self.delay_timer = self.registers[x];
             },
-            Chip8Op::SetSound(c) => {
-                self.sound_timer = c;
+            Chip8Op::SetSound(x) => {
+                self.sound_timer = self.registers[x];
             },
+            // I is a 16-bit register and wraps like one.
             Chip8Op::AddMemoryAddress(x) => {
-                self.ma += self.registers[x] as usize;
+                self.ma = (self.ma + self.registers[x] as usize) & 0xFFFF;
             },
             Chip8Op::GetSprite(x) => {
-                self.ma = (5 * self.registers[x]) as usize;
+                self.ma = (self.registers[x] & 0xF) as usize * 5;
             },
             Chip8Op::BinaryCoding(x) => {
-                self.memory[self.ma] = (self.registers[x] / 100) as u8;
-                self.memory[self.ma + 1] = ((self.registers[x] / 10) % 10) as u8;
-                self.memory[self.ma + 2] = ((self.registers[x] % 100) % 10) as u8;
+                let ma = self.ma;
+                self.write_mem(ma, self.registers[x] / 100)?;
+                self.write_mem(ma + 1, (self.registers[x] / 10) % 10)?;
+                self.write_mem(ma + 2, self.registers[x] % 10)?;
             },
             Chip8Op::RegisterDump(x) => {
+                let ma = self.ma;
                 for i in 0..x+1 {
-                    self.memory[self.ma] = self.registers[i];
-                    self.ma += 1;
+                    self.write_mem(ma + i, self.registers[i])?;
                 }
+                if self.quirks.load_store_increments_i { self.ma = (self.ma + x + 1) & 0xFFFF; }
             },
             Chip8Op::RegisterLoad(x) => {
                 for i in 0..x+1 {
-                    self.registers[i] = self.memory[self.ma];
-                    self.ma += 1;
+                    self.registers[i] = self.read_mem(self.ma + i)?;
                 }
+                if self.quirks.load_store_increments_i { self.ma = (self.ma + x + 1) & 0xFFFF; }
+            },
+            Chip8Op::ScrollDown(n) => {
+                self.display.scroll_down(self.planes, n as usize);
+                self.draw_flag = true;
+            },
+            Chip8Op::ScrollRight => {
+                self.display.scroll_right(self.planes, 4);
+                self.draw_flag = true;
+            },
+            Chip8Op::ScrollLeft => {
+                self.display.scroll_left(self.planes, 4);
+                self.draw_flag = true;
+            },
+            Chip8Op::Exit => {
+                self.no_advance();
+                self.exit_flag = true;
+            },
+            Chip8Op::LowRes => {
+                self.display.resize(DISPWIDTH, DISPHEIGHT);
+                self.draw_flag = true;
+            },
+            Chip8Op::HighRes => {
+                self.display.resize(HIRES_WIDTH, HIRES_HEIGHT);
+                self.draw_flag = true;
+            },
+            Chip8Op::GetBigSprite(x) => {
+                self.ma = BIG_FONTSET_START + 10 * (self.registers[x] & 0xF) as usize;
+            },
+            Chip8Op::SaveFlags(x) => {
+                self.rpl_flags[..x + 1].copy_from_slice(&self.registers[..x + 1]);
+            },
+            Chip8Op::LoadFlags(x) => {
+                self.registers[..x + 1].copy_from_slice(&self.rpl_flags[..x + 1]);
+            },
+            Chip8Op::ScrollUp(n) => {
+                self.display.scroll_up(self.planes, n as usize);
+                self.draw_flag = true;
+            },
+            Chip8Op::LongSetMemoryAddress => {
+                self.ma = self.fetch_operand()?;
+            },
+            Chip8Op::SaveRange(x, y) => {
+                let ma = self.ma;
+                for (offset, reg) in register_range(x, y).enumerate() {
+                    self.write_mem(ma + offset, self.registers[reg])?;
+                }
+            },
+            Chip8Op::LoadRange(x, y) => {
+                for (offset, reg) in register_range(x, y).enumerate() {
+                    self.registers[reg] = self.read_mem(self.ma + offset)?;
+                }
+            },
+            Chip8Op::SelectPlanes(n) => {
+                self.planes = n & 0x3;
+            },
+            Chip8Op::LoadAudio => {
+                let mut pattern = [0; 16];
+                for (offset, byte) in pattern.iter_mut().enumerate() {
+                    *byte = self.read_mem(self.ma + offset)?;
+                }
+                self.audio_pattern = Some(pattern);
+            },
+            Chip8Op::SetPitch(x) => {
+                self.pitch = self.registers[x];
             },
         }
         if self.trace_flag { print!("\tMemory after: {}\n\r", self.ma); }
         if self.trace_flag { print!("\tRegisters after: {:?}\n\r", self.registers) };
+        Ok(())
+    }
+}
+
+/// Puts a machine straight into a given state, so a single instruction can be
+/// tried without a program to set things up first. Built machines start with
+/// the random generator seeded with 0 unless told otherwise. Addresses and
+/// register numbers out of range panic, as indexing would.
+pub struct Chip8Builder {
+    chip8: Chip8,
+}
+
+impl Chip8Builder {
+    pub fn new() -> Self {
+        let mut chip8 = Chip8::new(Quirks::default());
+        chip8.rng = Rng::new(0);
+        Chip8Builder { chip8 }
+    }
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.chip8.quirks = quirks;
+        self
+    }
+    pub fn reg(mut self, x: usize, value: u8) -> Self {
+        self.chip8.registers[x] = value;
+        self
+    }
+    /// The I register.
+    pub fn i(mut self, addr: usize) -> Self {
+        self.chip8.ma = addr;
+        self
+    }
+    pub fn pc(mut self, addr: usize) -> Self {
+        self.chip8.pc = addr;
+        self
+    }
+    pub fn mem(mut self, addr: usize, bytes: &[u8]) -> Self {
+        self.chip8.memory[addr..addr + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+    /// Writes an instruction at the program counter as it is now.
+    pub fn op(self, opcode: u16) -> Self {
+        let pc = self.chip8.pc;
+        self.mem(pc, &[(opcode >> 8) as u8, opcode as u8])
+    }
+    /// Return addresses, oldest first.
+    pub fn stack(mut self, addrs: &[usize]) -> Self {
+        self.chip8.stack[..addrs.len()].copy_from_slice(addrs);
+        self.chip8.sp = addrs.len();
+        self
+    }
+    pub fn delay_timer(mut self, value: u8) -> Self {
+        self.chip8.delay_timer = value;
+        self
+    }
+    pub fn sound_timer(mut self, value: u8) -> Self {
+        self.chip8.sound_timer = value;
+        self
+    }
+    pub fn key(mut self, key: usize) -> Self {
+        self.chip8.keys[key] = true;
+        self
+    }
+    pub fn hires(mut self) -> Self {
+        self.chip8.display.resize(HIRES_WIDTH, HIRES_HEIGHT);
+        self
+    }
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.chip8.rng = Rng::new(seed);
+        self
+    }
+    pub fn build(self) -> Chip8 {
+        self.chip8
+    }
+}
+
+impl Default for Chip8Builder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -391,6 +1068,614 @@ mod tests {
     }
     #[test]
     fn decode_jump() {
-        assert_eq!(decode_opcode(0x1456), Chip8Op::Jump(0x0456));
+        assert_eq!(decode_opcode(0x1456), Some(Chip8Op::Jump(0x0456)));
+    }
+    #[test]
+    fn decode_invalid() {
+        assert_eq!(decode_opcode(0x5121), None);
+    }
+    #[test]
+    fn invalid_opcode_is_reported() {
+        let mut chip8 = Chip8::new(Quirks::default());
+        chip8.load(vec![0xE0, 0x00]).unwrap();
+        assert_eq!(chip8.emulate_cycle(),
+                   Err(Chip8Error::InvalidOpcode { opcode: 0xE000, address: 0x200 }));
+    }
+    #[test]
+    fn return_with_empty_stack() {
+        let mut chip8 = Chip8::new(Quirks::default());
+        chip8.load(vec![0x00, 0xEE]).unwrap();
+        assert_eq!(chip8.emulate_cycle(), Err(Chip8Error::StackUnderflow { address: 0x200 }));
+    }
+    #[test]
+    fn call_recursion_overflows() {
+        let mut chip8 = Chip8::new(Quirks::default());
+        chip8.load(vec![0x22, 0x00]).unwrap();
+        for _ in 0..STACKSIZE {
+            assert_eq!(chip8.emulate_cycle(), Ok(()));
+        }
+        assert_eq!(chip8.emulate_cycle(), Err(Chip8Error::StackOverflow { address: 0x200 }));
+    }
+    #[test]
+    fn pc_off_end_of_memory() {
+        let mut chip8 = Chip8::new(Quirks::default());
+        chip8.pc = MEMSIZE - 1;
+        assert_eq!(chip8.emulate_cycle(), Err(Chip8Error::PcOutOfBounds { pc: MEMSIZE - 1 }));
+    }
+    #[test]
+    fn pc_wraps_at_the_end_of_memory() {
+        // LD V0, 5 in the last word; SE V0, 5 just before it skips over it
+        let mut chip8 = Chip8Builder::new().pc(0xFFFE).op(0x6005).build();
+        chip8.memory[0xFFFC..0xFFFE].copy_from_slice(&[0x30, 0x05]);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0);
+        let mut restored = Chip8::default();
+        restored.load_state(&chip8.save_state()).unwrap();
+        assert_eq!(restored.pc, 0);
+        restored.pc = 0xFFFC;
+        restored.emulate_cycle().unwrap();
+        assert_eq!(restored.pc, 0);
+    }
+    #[test]
+    fn timers_tick_per_frame_not_per_cycle() {
+        let mut chip8 = Chip8::new(Quirks::default());
+        // V3 = 0x10; delay = V3; sound = V3; jump to self
+        chip8.load(vec![0x63, 0x10, 0xF3, 0x15, 0xF3, 0x18, 0x12, 0x06]).unwrap();
+        for _ in 0..10 {
+            chip8.emulate_cycle().unwrap();
+        }
+        assert_eq!(chip8.delay_timer, 0x10);
+        assert_eq!(chip8.sound_timer, 0x10);
+        chip8.tick_timers();
+        assert_eq!(chip8.delay_timer, 0x0F);
+        assert_eq!(chip8.sound_timer, 0x0F);
+        assert!(chip8.buzzer_on());
+        assert_eq!(chip8.poll_sound_event(), Some(SoundEvent::Start(Tone::Buzzer)));
+        assert_eq!(chip8.poll_sound_event(), None);
+        for _ in 0..0x0F {
+            chip8.tick_timers();
+        }
+        assert!(!chip8.buzzer_on());
+        assert_eq!(chip8.poll_sound_event(), Some(SoundEvent::Stop));
+        assert_eq!(chip8.poll_sound_event(), None);
+    }
+    fn run(quirks: Quirks, program: Vec<u8>, cycles: usize) -> Chip8 {
+        let mut chip8 = Chip8::new(quirks);
+        chip8.load(program).unwrap();
+        for _ in 0..cycles {
+            chip8.emulate_cycle().unwrap();
+        }
+        chip8
+    }
+    #[test]
+    fn shift_quirk() {
+        // V1 = 0x81; V2 = 0x04; V1 <<= V2
+        let program = vec![0x61, 0x81, 0x62, 0x04, 0x81, 0x2E];
+        let vip = run(Quirks::cosmac_vip(), program.clone(), 3);
+        assert_eq!((vip.registers[1], vip.registers[2], vip.registers[0xF]), (0x08, 0x04, 0));
+        let schip = run(Quirks::superchip(), program, 3);
+        assert_eq!((schip.registers[1], schip.registers[2], schip.registers[0xF]), (0x02, 0x04, 1));
+    }
+    #[test]
+    fn vf_reset_quirk() {
+        // VF = 1; V0 |= V1
+        let program = vec![0x6F, 0x01, 0x80, 0x11];
+        assert_eq!(run(Quirks::cosmac_vip(), program.clone(), 2).registers[0xF], 0);
+        assert_eq!(run(Quirks::superchip(), program, 2).registers[0xF], 1);
+    }
+    #[test]
+    fn jump_quirk() {
+        // V0 = 2; V3 = 4; jump 0x300 + V0 or V3
+        let program = vec![0x60, 0x02, 0x63, 0x04, 0xB3, 0x00];
+        assert_eq!(run(Quirks::cosmac_vip(), program.clone(), 3).pc, 0x302);
+        assert_eq!(run(Quirks::superchip(), program, 3).pc, 0x304);
+    }
+    #[test]
+    fn load_store_quirk() {
+        // I = 0x300; dump V0..V2
+        let program = vec![0xA3, 0x00, 0xF2, 0x55];
+        assert_eq!(run(Quirks::cosmac_vip(), program.clone(), 2).ma, 0x303);
+        assert_eq!(run(Quirks::superchip(), program, 2).ma, 0x300);
+    }
+    #[test]
+    fn clip_quirk() {
+        // V0 = 62; I = font 0; draw 5 rows at (62, 0)
+        let program = vec![0x60, 0x3E, 0xA0, 0x00, 0xD0, 0x15];
+        let clipped = run(Quirks::superchip(), program.clone(), 3);
+        assert!(!clipped.display.get(0, 0));
+        assert!(clipped.display.get(63, 0));
+        let mut quirks = Quirks::superchip();
+        quirks.clip_sprites = false;
+        let wrapped = run(quirks, program, 3);
+        assert!(wrapped.display.get(0, 0));
+        assert!(wrapped.display.get(63, 0));
+    }
+    #[test]
+    fn display_wait_quirk() {
+        // Two draws in a row only complete one per frame.
+        let program = vec![0xD0, 0x01, 0xD0, 0x01];
+        let mut chip8 = run(Quirks::cosmac_vip(), program, 3);
+        assert_eq!(chip8.pc, 0x202);
+        chip8.tick_timers();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x204);
+    }
+    #[test]
+    fn decode_superchip() {
+        assert_eq!(decode_opcode(0x00C4), Some(Chip8Op::ScrollDown(4)));
+        assert_eq!(decode_opcode(0x00FF), Some(Chip8Op::HighRes));
+        assert_eq!(decode_opcode(0xF530), Some(Chip8Op::GetBigSprite(5)));
+    }
+    #[test]
+    fn high_res_big_sprite() {
+        // hires; V0 = 120; I = big 8; draw 16x16 at (120, 0)
+        let program = vec![0x00, 0xFF, 0x60, 0x78, 0x61, 0x08, 0xF1, 0x30, 0xD0, 0x20];
+        let chip8 = run(Quirks::superchip(), program, 5);
+        assert_eq!((chip8.display.width(), chip8.display.height()), (128, 64));
+        assert_eq!(chip8.ma, BIG_FONTSET_START + 80);
+        assert!(chip8.display.get(127, 0));
+        assert!(!chip8.display.get(0, 0));
+    }
+    #[test]
+    fn rpl_flags_round_trip() {
+        // V0 = 1; V1 = 2; save; clear; load
+        let program = vec![0x60, 0x01, 0x61, 0x02, 0xF1, 0x75, 0x60, 0x00, 0x61, 0x00, 0xF1, 0x85];
+        let chip8 = run(Quirks::superchip(), program, 6);
+        assert_eq!(&chip8.registers[..2], &[1, 2]);
+    }
+    #[test]
+    fn exit_stops_at_instruction() {
+        let chip8 = run(Quirks::superchip(), vec![0x00, 0xFD], 2);
+        assert!(chip8.exit_flag);
+        assert_eq!(chip8.pc, 0x200);
+    }
+    #[test]
+    fn long_index_load_is_skipped_whole() {
+        // V0 = 0; skip if V0 == 0 over F000 1234; I = 0x300
+        let program = vec![0x60, 0x00, 0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0xA3, 0x00];
+        assert_eq!(run(Quirks::xochip(), program.clone(), 3).ma, 0x300);
+        let mut chip8 = run(Quirks::xochip(), program, 1);
+        chip8.pc = 0x204;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!((chip8.ma, chip8.pc), (0x1234, 0x208));
+    }
+    #[test]
+    fn register_range_save_load_reversed() {
+        // V1 = 1; V2 = 2; I = 0x300; save V2..V1; load V1..V2
+        let program = vec![0x61, 0x01, 0x62, 0x02, 0xA3, 0x00, 0x52, 0x12, 0x51, 0x23];
+        let chip8 = run(Quirks::xochip(), program, 5);
+        assert_eq!(&chip8.memory[0x300..0x302], &[2, 1]);
+        assert_eq!(&chip8.registers[1..3], &[2, 1]);
+        assert_eq!(chip8.ma, 0x300);
+    }
+    #[test]
+    fn draw_on_both_planes() {
+        // planes 3; I = 0x300; draw 1 row, taking one byte per plane
+        let program = vec![0xF3, 0x01, 0xA3, 0x00, 0xD0, 0x01];
+        let mut chip8 = Chip8::new(Quirks::xochip());
+        chip8.load(program).unwrap();
+        chip8.memory[0x300] = 0x80;
+        chip8.memory[0x301] = 0xC0;
+        for _ in 0..3 {
+            chip8.emulate_cycle().unwrap();
+        }
+        assert_eq!(chip8.display.color(0, 0), 3);
+        assert_eq!(chip8.display.color(1, 0), 2);
+    }
+    #[test]
+    fn audio_pattern_and_pitch() {
+        // I = font 0; load pattern; V0 = 112; pitch = V0
+        let program = vec![0xA0, 0x00, 0xF0, 0x02, 0x60, 0x70, 0xF0, 0x3A];
+        let chip8 = run(Quirks::xochip(), program, 4);
+        assert_eq!(chip8.audio_pattern().unwrap()[..5], FONTSET[..5]);
+        assert_eq!(chip8.audio_sample_rate(), 8000.0);
+    }
+    #[test]
+    fn memory_accesses_are_recorded() {
+        // V0 = 0x7B; I = 0x300; BCD of V0; dump V0
+        let program = vec![0x60, 0x7B, 0xA3, 0x00, 0xF0, 0x33, 0xF0, 0x55];
+        let mut chip8 = run(Quirks::default(), program, 3);
+        let writes: Vec<(usize, u8)> = chip8.memory_accesses().iter()
+            .map(|access| (access.address, access.value))
+            .collect();
+        assert_eq!(writes, vec![(0x300, 1), (0x301, 2), (0x302, 3)]);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.memory_accesses(),
+                   &[MemoryAccess { address: 0x300, kind: AccessKind::Write, value: 0x7B }]);
+    }
+    #[test]
+    fn rom_too_large() {
+        let mut chip8 = Chip8::new(Quirks::default());
+        assert_eq!(chip8.load(vec![0; MEMSIZE]), Err(Chip8Error::RomTooLarge { size: MEMSIZE }));
+    }
+    #[test]
+    fn save_state_round_trip() {
+        // high-res; V0 = 5; I = 0x300; dump V0; call 0x20A
+        let program = vec![0x00, 0xFF, 0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0x22, 0x0A];
+        let chip8 = run(Quirks::superchip(), program, 5);
+        let state = chip8.save_state();
+        let mut restored = Chip8::default();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.pc(), 0x20A);
+        assert_eq!(restored.stack(), &[0x20A]);
+        assert_eq!(restored.registers()[0], 5);
+        assert_eq!(restored.memory()[0x300], 5);
+        assert_eq!(restored.display.width(), HIRES_WIDTH);
+        assert_eq!(restored.quirks, Quirks::superchip());
+        assert!(restored.draw_flag);
+    }
+    #[test]
+    fn bad_save_state_is_refused() {
+        let mut chip8 = Chip8::default();
+        let mut state = chip8.save_state();
+        chip8.registers[0] = 7;
+        assert_eq!(chip8.load_state(&state[..100]),
+                   Err(Chip8Error::BadState { reason: "file is truncated" }));
+        state[4] += 1;
+        assert_eq!(chip8.load_state(&state),
+                   Err(Chip8Error::BadState { reason: "unsupported version" }));
+        assert_eq!(chip8.load_state(b"PONG"), Err(Chip8Error::BadState { reason: "unrecognised file type" }));
+        assert_eq!(chip8.registers[0], 7);
+    }
+    #[test]
+    fn save_state_with_impossible_colours_is_refused() {
+        // A pixel lit on a third plane would index past the palette.
+        let mut bad = Chip8::default();
+        bad.display.flip(0, 0, 0x4);
+        assert_eq!(Chip8::default().load_state(&bad.save_state()),
+                   Err(Chip8Error::BadState { reason: "display is malformed" }));
+        let bad = Chip8 { planes: 0x4, ..Chip8::default() };
+        assert_eq!(Chip8::default().load_state(&bad.save_state()),
+                   Err(Chip8Error::BadState { reason: "plane selection out of range" }));
+    }
+    #[test]
+    fn seeded_random_repeats() {
+        // V0 = random; V1 = random
+        let program = vec![0xC0, 0xFF, 0xC1, 0xFF];
+        let mut first = Chip8::default();
+        first.seed_rng(7);
+        first.load(program.clone()).unwrap();
+        first.emulate_cycle().unwrap();
+        let mut second = Chip8::default();
+        second.load_state(&first.save_state()).unwrap();
+        first.emulate_cycle().unwrap();
+        second.emulate_cycle().unwrap();
+        assert_eq!(first.registers(), second.registers());
+        let mut again = Chip8::default();
+        again.seed_rng(7);
+        again.load(program).unwrap();
+        again.emulate_cycle().unwrap();
+        again.emulate_cycle().unwrap();
+        assert_eq!(first.registers(), again.registers());
+    }
+
+    // Runs one instruction on a machine from the builder. The op_ tests
+    // below cover every Chip8Op between them.
+    fn step(builder: Chip8Builder, opcode: u16) -> Chip8 {
+        let mut chip8 = builder.op(opcode).build();
+        chip8.emulate_cycle().unwrap();
+        chip8
+    }
+    fn lit_pixels(chip8: &Chip8) -> Vec<(usize, usize)> {
+        let width = chip8.display.width();
+        chip8.display.pixels().iter().enumerate()
+            .filter(|&(_, &color)| color != 0)
+            .map(|(i, _)| (i % width, i / width))
+            .collect()
+    }
+    #[test]
+    fn op_display_clear() {
+        let mut chip8 = Chip8Builder::new().op(0x00E0).build();
+        chip8.display.flip(3, 4, 1);
+        chip8.emulate_cycle().unwrap();
+        assert!(lit_pixels(&chip8).is_empty());
+        assert!(chip8.draw_flag);
+    }
+    #[test]
+    fn op_return() {
+        let chip8 = step(Chip8Builder::new().stack(&[0x300, 0x456]), 0x00EE);
+        assert_eq!(chip8.pc(), 0x456);
+        assert_eq!(chip8.stack(), &[0x300]);
+    }
+    #[test]
+    fn op_jump() {
+        assert_eq!(step(Chip8Builder::new(), 0x1ABC).pc(), 0xABC);
+    }
+    #[test]
+    fn op_call_up_to_stack_depth() {
+        let chip8 = step(Chip8Builder::new(), 0x2ABC);
+        assert_eq!(chip8.pc(), 0xABC);
+        assert_eq!(chip8.stack(), &[0x202]);
+        // The sixteenth entry fits; a seventeenth does not, and the call is
+        // not made.
+        let chip8 = step(Chip8Builder::new().stack(&[0x300; 15]), 0x2ABC);
+        assert_eq!(chip8.sp(), 16);
+        let mut chip8 = Chip8Builder::new().stack(&[0x300; 16]).op(0x2ABC).build();
+        assert_eq!(chip8.emulate_cycle(), Err(Chip8Error::StackOverflow { address: 0x200 }));
+        assert_eq!(chip8.sp(), 16);
+    }
+    #[test]
+    fn op_skips() {
+        // (opcode, V1, V2, skipped)
+        let cases = [(0x3105, 5, 0, true), (0x3106, 5, 0, false),
+                     (0x4106, 5, 0, true), (0x4105, 5, 0, false),
+                     (0x5120, 5, 5, true), (0x5120, 5, 6, false),
+                     (0x9120, 5, 6, true), (0x9120, 5, 5, false)];
+        for &(opcode, v1, v2, skipped) in cases.iter() {
+            let chip8 = step(Chip8Builder::new().reg(1, v1).reg(2, v2), opcode);
+            assert_eq!(chip8.pc(), if skipped { 0x204 } else { 0x202 },
+                       "{:04X} with V1 = {}, V2 = {}", opcode, v1, v2);
+        }
+        // A skip steps over all four bytes of F000 NNNN.
+        let chip8 = step(Chip8Builder::new().reg(1, 5).mem(0x202, &[0xF0, 0x00, 0x12, 0x34]), 0x3105);
+        assert_eq!(chip8.pc(), 0x206);
+    }
+    #[test]
+    fn op_set_and_add_constant() {
+        assert_eq!(step(Chip8Builder::new(), 0x6A42).registers()[0xA], 0x42);
+        // 7XNN wraps and never touches VF.
+        let chip8 = step(Chip8Builder::new().reg(0xA, 250).reg(0xF, 9), 0x7A0A);
+        assert_eq!(chip8.registers()[0xA], 4);
+        assert_eq!(chip8.registers()[0xF], 9);
+    }
+    #[test]
+    fn op_register_arithmetic() {
+        let vip = Quirks::cosmac_vip();
+        let schip = Quirks::superchip();
+        // (quirks, opcode, V1, V2, V1 after, VF after), with VF at 7 before.
+        let cases = [(vip, 0x8120, 3, 9, 9, 7),
+                     (vip, 0x8121, 0x0F, 0xF0, 0xFF, 0),
+                     (schip, 0x8121, 0x0F, 0xF0, 0xFF, 7),
+                     (vip, 0x8122, 0x3C, 0x0F, 0x0C, 0),
+                     (schip, 0x8122, 0x3C, 0x0F, 0x0C, 7),
+                     (vip, 0x8123, 0x3C, 0x0F, 0x33, 0),
+                     (schip, 0x8123, 0x3C, 0x0F, 0x33, 7),
+                     (vip, 0x8124, 10, 20, 30, 0),
+                     (vip, 0x8124, 200, 100, 44, 1),
+                     (vip, 0x8124, 255, 1, 0, 1),
+                     (vip, 0x8125, 30, 10, 20, 1),
+                     (vip, 0x8125, 10, 30, 236, 0),
+                     (vip, 0x8125, 7, 7, 0, 1),
+                     (vip, 0x8127, 10, 30, 20, 1),
+                     (vip, 0x8127, 30, 10, 236, 0),
+                     (vip, 0x8127, 7, 7, 0, 1),
+                     // The VIP shifts VY into VX; the SUPER-CHIP shifts VX.
+                     (vip, 0x8126, 0xFF, 0x05, 0x02, 1),
+                     (schip, 0x8126, 0x04, 0x05, 0x02, 0),
+                     // The carry out of a left shift is 1, not the 0x80 bit.
+                     (vip, 0x812E, 0x00, 0x81, 0x02, 1),
+                     (schip, 0x812E, 0x41, 0x81, 0x82, 0)];
+        for &(quirks, opcode, v1, v2, result, flag) in cases.iter() {
+            let chip8 = step(Chip8Builder::new().quirks(quirks).reg(1, v1).reg(2, v2).reg(0xF, 7), opcode);
+            assert_eq!((chip8.registers()[1], chip8.registers()[0xF]), (result, flag),
+                       "{:04X} with V1 = {}, V2 = {}, {:?}", opcode, v1, v2, quirks);
+        }
+    }
+    #[test]
+    fn op_arithmetic_with_vf_as_operand() {
+        // The flag is written after the result, so it wins when VF is VX.
+        assert_eq!(step(Chip8Builder::new().reg(0xF, 200).reg(1, 100), 0x8F14).registers()[0xF], 1);
+        assert_eq!(step(Chip8Builder::new().reg(0xF, 10).reg(1, 30), 0x8F15).registers()[0xF], 0);
+        assert_eq!(step(Chip8Builder::new().reg(0xF, 3), 0x8FF6).registers()[0xF], 1);
+        assert_eq!(step(Chip8Builder::new().reg(0xF, 0x40), 0x8FFE).registers()[0xF], 0);
+        // As VY it is read before being overwritten.
+        let chip8 = step(Chip8Builder::new().reg(1, 200).reg(0xF, 100), 0x81F4);
+        assert_eq!((chip8.registers()[1], chip8.registers()[0xF]), (44, 1));
+    }
+    #[test]
+    fn op_set_index() {
+        assert_eq!(step(Chip8Builder::new(), 0xA123).index(), 0x123);
+    }
+    #[test]
+    fn op_jump_plus() {
+        let machine = || Chip8Builder::new().reg(0, 4).reg(1, 8);
+        assert_eq!(step(machine(), 0xB123).pc(), 0x127);
+        assert_eq!(step(machine().quirks(Quirks::superchip()), 0xB123).pc(), 0x12B);
+    }
+    #[test]
+    fn op_random() {
+        let chip8 = step(Chip8Builder::new().seed(5), 0xC10F);
+        assert_eq!(chip8.registers()[1], Rng::new(5).next_u8() & 0x0F);
+        assert_eq!(step(Chip8Builder::new().reg(1, 9), 0xC100).registers()[1], 0);
+    }
+    #[test]
+    fn op_draw_sprite() {
+        let sprite = || Chip8Builder::new().i(0x300).mem(0x300, &[0xC0]).reg(2, 31);
+        let chip8 = step(sprite().reg(1, 10), 0xD121);
+        assert_eq!(lit_pixels(&chip8), vec![(10, 31), (11, 31)]);
+        assert_eq!(chip8.registers()[0xF], 0);
+        // Drawing again erases the sprite and reports the collision.
+        let mut chip8 = sprite().reg(1, 10).op(0xD121).mem(0x202, &[0x12, 0x00]).build();
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.tick_timers();
+        chip8.emulate_cycle().unwrap();
+        assert!(lit_pixels(&chip8).is_empty());
+        assert_eq!(chip8.registers()[0xF], 1);
+        // At the edge the VIP clips and XO-CHIP wraps.
+        assert_eq!(lit_pixels(&step(sprite().reg(1, 63), 0xD121)), vec![(63, 31)]);
+        let chip8 = step(sprite().reg(1, 63).quirks(Quirks::xochip()), 0xD121);
+        assert_eq!(lit_pixels(&chip8), vec![(0, 31), (63, 31)]);
+        // DXY0 draws 16x16 in high res.
+        let chip8 = step(Chip8Builder::new().hires().i(0x300).mem(0x300, &[0xFF; 32]), 0xD120);
+        assert_eq!(lit_pixels(&chip8).len(), 256);
+    }
+    #[test]
+    fn op_key_skips() {
+        // (opcode, key 3 down, skipped), with V1 = 3.
+        let cases = [(0xE19E, true, true), (0xE19E, false, false),
+                     (0xE1A1, true, false), (0xE1A1, false, true)];
+        for &(opcode, down, skipped) in cases.iter() {
+            let machine = Chip8Builder::new().reg(1, 3);
+            let chip8 = step(if down { machine.key(3) } else { machine }, opcode);
+            assert_eq!(chip8.pc(), if skipped { 0x204 } else { 0x202 }, "{:04X} with key down {}", opcode, down);
+        }
+        // Only the low nibble picks the key, so any register value is safe.
+        assert_eq!(step(Chip8Builder::new().reg(1, 0xF3).key(3), 0xE19E).pc(), 0x204);
+    }
+    #[test]
+    fn op_timers() {
+        assert_eq!(step(Chip8Builder::new().delay_timer(33), 0xF107).registers()[1], 33);
+        assert_eq!(step(Chip8Builder::new().reg(1, 44), 0xF115).delay_timer(), 44);
+        assert_eq!(step(Chip8Builder::new().reg(1, 55), 0xF118).sound_timer(), 55);
+    }
+    #[test]
+    fn op_get_key() {
+        let mut chip8 = step(Chip8Builder::new(), 0xF30A);
+        assert!(chip8.wait_for_key_flag);
+        chip8.give_key(0xB);
+        assert_eq!(chip8.registers()[3], 0xB);
     }
-}
\ No newline at end of file
+    #[test]
+    fn op_add_index() {
+        assert_eq!(step(Chip8Builder::new().i(0x300).reg(1, 0x20), 0xF11E).index(), 0x320);
+        // I is 16 bits wide, so it wraps rather than growing past what a save
+        // state can hold.
+        let chip8 = step(Chip8Builder::new().i(0xFFFF).reg(1, 0x2), 0xF11E);
+        assert_eq!(chip8.index(), 0x1);
+        let mut restored = Chip8::default();
+        restored.load_state(&chip8.save_state()).unwrap();
+        assert_eq!(restored.index(), 0x1);
+    }
+    #[test]
+    fn op_font_sprites() {
+        assert_eq!(step(Chip8Builder::new().reg(1, 0xA), 0xF129).index(), 50);
+        assert_eq!(step(Chip8Builder::new().reg(1, 0xFA), 0xF129).index(), 50);
+        assert_eq!(step(Chip8Builder::new().reg(1, 2), 0xF130).index(), BIG_FONTSET_START + 20);
+    }
+    #[test]
+    fn op_binary_coding() {
+        for &(value, digits) in [(255, [2, 5, 5]), (0, [0, 0, 0]), (7, [0, 0, 7]), (120, [1, 2, 0])].iter() {
+            let chip8 = step(Chip8Builder::new().i(0x300).reg(1, value), 0xF133);
+            assert_eq!(&chip8.memory()[0x300..0x303], &digits, "BCD of {}", value);
+            assert_eq!(chip8.index(), 0x300);
+        }
+    }
+    #[test]
+    fn op_register_dump_and_load() {
+        let machine = || Chip8Builder::new().i(0x300).reg(0, 1).reg(1, 2).reg(2, 3).reg(3, 4);
+        let chip8 = step(machine(), 0xF255);
+        assert_eq!(&chip8.memory()[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(chip8.index(), 0x303);
+        assert_eq!(step(machine().quirks(Quirks::superchip()), 0xF255).index(), 0x300);
+        let chip8 = step(Chip8Builder::new().i(0x300).mem(0x300, &[9, 8, 7, 6]), 0xF265);
+        assert_eq!(&chip8.registers()[..4], &[9, 8, 7, 0]);
+        assert_eq!(chip8.index(), 0x303);
+        assert_eq!(step(Chip8Builder::new().i(0xFFFD), 0xF255).index(), 0x0);
+        assert_eq!(step(Chip8Builder::new().i(0xFFFD), 0xF265).index(), 0x0);
+    }
+    #[test]
+    fn op_scrolls() {
+        // (opcode, where the pixel at (10, 10) ends up)
+        let cases = [(0x00C2, (10, 12)), (0x00FB, (14, 10)), (0x00FC, (6, 10)), (0x00D2, (10, 8))];
+        for &(opcode, moved) in cases.iter() {
+            let mut chip8 = Chip8Builder::new().hires().op(opcode).build();
+            chip8.display.flip(10, 10, 1);
+            chip8.emulate_cycle().unwrap();
+            assert_eq!(lit_pixels(&chip8), vec![moved], "{:04X}", opcode);
+        }
+    }
+    #[test]
+    fn op_exit() {
+        let chip8 = step(Chip8Builder::new(), 0x00FD);
+        assert!(chip8.exit_flag);
+        assert_eq!(chip8.pc(), 0x200);
+    }
+    #[test]
+    fn op_resolution() {
+        let chip8 = step(Chip8Builder::new(), 0x00FF);
+        assert_eq!((chip8.display.width(), chip8.display.height()), (128, 64));
+        let chip8 = step(Chip8Builder::new().hires(), 0x00FE);
+        assert_eq!((chip8.display.width(), chip8.display.height()), (64, 32));
+    }
+    #[test]
+    fn op_rpl_flags() {
+        let mut chip8 = Chip8Builder::new().reg(0, 5).reg(1, 6).reg(2, 7)
+            .mem(0x200, &[0xF1, 0x75, 0x60, 0x00, 0x61, 0x00, 0x62, 0x00, 0xF2, 0x85])
+            .build();
+        for _ in 0..5 {
+            chip8.emulate_cycle().unwrap();
+        }
+        // Only V0 and V1 were saved; V2 gets whatever the flags held.
+        assert_eq!(&chip8.registers()[..3], &[5, 6, 0]);
+    }
+    #[test]
+    fn op_long_set_index() {
+        let chip8 = step(Chip8Builder::new().mem(0x202, &[0x12, 0x34]), 0xF000);
+        assert_eq!(chip8.index(), 0x1234);
+        assert_eq!(chip8.pc(), 0x204);
+    }
+    #[test]
+    fn op_save_and_load_range() {
+        let machine = || Chip8Builder::new().i(0x300).reg(1, 1).reg(2, 2).reg(3, 3);
+        assert_eq!(&step(machine(), 0x5132).memory()[0x300..0x303], &[1, 2, 3]);
+        assert_eq!(&step(machine(), 0x5312).memory()[0x300..0x303], &[3, 2, 1]);
+        let chip8 = step(Chip8Builder::new().i(0x300).mem(0x300, &[7, 8]), 0x5213);
+        assert_eq!(&chip8.registers()[..4], &[0, 8, 7, 0]);
+        assert_eq!(chip8.index(), 0x300);
+    }
+    #[test]
+    fn op_select_planes() {
+        let mut chip8 = Chip8Builder::new().i(0x300).mem(0x300, &[0x80])
+            .mem(0x200, &[0xF2, 0x01, 0xD0, 0x01])
+            .build();
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.display.color(0, 0), 2);
+    }
+    #[test]
+    fn op_audio() {
+        let pattern = [0xAA; 16];
+        let chip8 = step(Chip8Builder::new().i(0x300).mem(0x300, &pattern), 0xF002);
+        assert_eq!(chip8.audio_pattern(), Some(pattern));
+        assert_eq!(step(Chip8Builder::new().reg(1, 112), 0xF13A).audio_sample_rate(), 8000.0);
+    }
+    #[test]
+    fn step_stands_still_while_waiting_for_a_key() {
+        // LD V2, K; LD V3, 7
+        let mut chip8 = Chip8Builder::new().mem(0x200, &[0xF2, 0x0A, 0x63, 0x07]).build();
+        chip8.step().unwrap();
+        assert_eq!(chip8.waiting_for_key(), Some(2));
+        chip8.step().unwrap();
+        assert_eq!(chip8.pc(), 0x202);
+        chip8.give_key(0xB);
+        assert_eq!(chip8.waiting_for_key(), None);
+        assert_eq!(chip8.registers()[2], 0xB);
+        chip8.step().unwrap();
+        assert_eq!(chip8.registers()[3], 7);
+    }
+    #[test]
+    fn run_frame_and_run_until() {
+        // LD V0, 3; LD DT, V0; ADD V1, 1; JP 0x204
+        let rom = [0x60, 0x03, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x04];
+        let mut chip8 = Chip8Builder::new().mem(0x200, &rom).build();
+        chip8.run_frame(10).unwrap();
+        assert_eq!(chip8.registers()[1], 4);
+        assert_eq!(chip8.delay_timer(), 2);
+        assert_eq!(chip8.run_until(100, |chip8| chip8.registers()[1] == 10).unwrap(), 11);
+        assert_eq!(chip8.run_until(5, |_| false).unwrap(), 5);
+        // An exited program runs no further.
+        let mut chip8 = Chip8Builder::new().op(0x00FD).build();
+        chip8.run_frame(10).unwrap();
+        assert!(chip8.exited());
+        assert_eq!(chip8.run_until(10, |_| false).unwrap(), 0);
+    }
+    #[test]
+    fn cpu_state_round_trips() {
+        let chip8 = Chip8Builder::new().reg(4, 0x44).i(0x345).pc(0x260).stack(&[0x202, 0x208])
+            .delay_timer(9).sound_timer(3).build();
+        let state = chip8.cpu_state();
+        assert_eq!(state.registers[4], 0x44);
+        assert_eq!((state.index, state.pc, state.sp), (0x345, 0x260, 2));
+        assert_eq!(&state.stack[..state.sp], chip8.stack());
+        let mut other = Chip8::default();
+        other.set_cpu_state(&state);
+        assert_eq!(other.cpu_state(), state);
+        // Out of range values are clamped rather than trusted.
+        other.set_cpu_state(&CpuState { pc: 0x12345, sp: 40, ..state });
+        assert_eq!((other.pc(), other.sp()), (0x2345, STACKSIZE));
+        other.set_stack(&[0x300; 20]);
+        assert_eq!(other.stack(), &[0x300; STACKSIZE][..]);
+    }
+}
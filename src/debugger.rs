@@ -0,0 +1,521 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use cpu::{Chip8, Chip8Op, AccessKind};
+use rewind::RewindBuffer;
+use NUM_REGS;
+
+// Room for the states before roughly the last hundred thousand instructions.
+const HISTORY_BYTES: usize = 1 << 20;
+
+const HELP: &str = "\
+c, continue       resume execution
+p, pause          pause execution
+s, step           execute one instruction
+back              undo the last instruction stepped through
+n, next           step over a subroutine call
+f, finish         run until the current subroutine returns
+b [ADDR]          set a breakpoint, or list breakpoints
+d ADDR            delete a breakpoint
+r, regs           show registers, stack, timers and keypad
+x ADDR [LEN]      dump memory
+watch ADDR [LEN]  stop when memory is written
+rwatch ADDR [LEN] stop when memory is read
+awatch ADDR [LEN] stop when memory is read or written
+watch vX          stop when register VX changes
+watch             list watchpoints
+unwatch N         delete watchpoint N
+h, help           show this help";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Continue,
+    Pause,
+    Step,
+    Back,
+    Next,
+    Finish,
+    Break(usize),
+    ListBreakpoints,
+    Delete(usize),
+    Registers,
+    Memory(usize, usize),
+    Watch(Watchpoint),
+    ListWatchpoints,
+    Unwatch(usize),
+    Help,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watchpoint {
+    // Memory from start up to but not including end.
+    Memory { start: usize, end: usize, on_read: bool, on_write: bool },
+    Register(usize),
+}
+
+impl Watchpoint {
+    fn describe(&self) -> String {
+        match *self {
+            Watchpoint::Memory { start, end, on_read, on_write } => {
+                let kind = match (on_read, on_write) {
+                    (true, true) => "access",
+                    (true, false) => "read",
+                    _ => "write",
+                };
+                format!("{} {:03X}..{:03X}", kind, start, end)
+            },
+            Watchpoint::Register(x) => format!("V{:X}", x),
+        }
+    }
+}
+
+fn parse_addr(word: &str) -> Result<usize, String> {
+    let digits = word.trim_start_matches("0x").trim_start_matches("0X");
+    usize::from_str_radix(digits, 16).map_err(|_| format!("bad address '{}'", word))
+}
+
+fn parse_len(word: &str) -> Result<usize, String> {
+    word.parse().map_err(|_| format!("bad length '{}'", word))
+}
+
+fn parse_watch(words: &[&str], on_read: bool, on_write: bool) -> Result<Command, String> {
+    let (start, len) = match *words {
+        [addr] => (parse_addr(addr)?, 1),
+        [addr, len] => (parse_addr(addr)?, parse_len(len)?),
+        _ => return Err("expected an address and optional length".to_string()),
+    };
+    let end = start.checked_add(len).ok_or_else(|| "range out of bounds".to_string())?;
+    Ok(Command::Watch(Watchpoint::Memory { start, end, on_read, on_write }))
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["c"] | ["continue"] => Ok(Command::Continue),
+        ["p"] | ["pause"] => Ok(Command::Pause),
+        ["s"] | ["step"] => Ok(Command::Step),
+        ["back"] => Ok(Command::Back),
+        ["n"] | ["next"] => Ok(Command::Next),
+        ["f"] | ["finish"] => Ok(Command::Finish),
+        ["b"] => Ok(Command::ListBreakpoints),
+        ["b", addr] => Ok(Command::Break(parse_addr(addr)?)),
+        ["d", addr] => Ok(Command::Delete(parse_addr(addr)?)),
+        ["r"] | ["regs"] => Ok(Command::Registers),
+        ["x", addr] => Ok(Command::Memory(parse_addr(addr)?, 16)),
+        ["x", addr, len] => Ok(Command::Memory(parse_addr(addr)?, parse_len(len)?)),
+        ["watch"] => Ok(Command::ListWatchpoints),
+        ["watch", reg] if reg.starts_with('v') || reg.starts_with('V') => {
+            match usize::from_str_radix(&reg[1..], 16) {
+                Ok(x) if x < NUM_REGS => Ok(Command::Watch(Watchpoint::Register(x))),
+                _ => Err(format!("bad register '{}'", reg)),
+            }
+        },
+        ["watch", rest @ ..] => parse_watch(rest, false, true),
+        ["rwatch", rest @ ..] => parse_watch(rest, true, false),
+        ["awatch", rest @ ..] => parse_watch(rest, true, true),
+        ["unwatch", n] => Ok(Command::Unwatch(parse_len(n)?)),
+        ["h"] | ["help"] => Ok(Command::Help),
+        _ => Err(format!("unknown command '{}', try 'help'", line.trim())),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunMode {
+    Paused,
+    Running,
+    Step,
+    // Run until execution comes back to pc with the stack at depth sp.
+    StepOver { pc: usize, sp: usize },
+    // Run until the stack is shallower than sp.
+    Finish { sp: usize },
+}
+
+// Decides when the game loop may execute instructions. The loop asks
+// before_cycle whether to run the instruction at pc and reports each executed
+// instruction to after_cycle; anything worth telling the user collects in an
+// output buffer read with take_output.
+pub struct Debugger {
+    mode: RunMode,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    registers_before: [u8; NUM_REGS],
+    // The machine before each instruction stepped through. Nothing is kept
+    // while running freely, since snapshotting every instruction is costly.
+    history: RewindBuffer,
+    // Set when resuming so the breakpoint we are stopped on does not fire again.
+    leaving_breakpoint: bool,
+    output: String,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    // The debugger starts paused so breakpoints can be set before the game runs.
+    pub fn new() -> Self {
+        Debugger { mode: RunMode::Paused,
+                   breakpoints: BTreeSet::new(),
+                   watchpoints: Vec::new(),
+                   registers_before: [0; NUM_REGS],
+                   history: RewindBuffer::new(HISTORY_BYTES),
+                   leaving_breakpoint: false,
+                   output: String::new(),
+        }
+    }
+    pub fn is_paused(&self) -> bool {
+        self.mode == RunMode::Paused
+    }
+    pub fn take_output(&mut self) -> Option<String> {
+        if self.output.is_empty() {
+            None
+        } else {
+            Some(self.output.split_off(0))
+        }
+    }
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.insert(addr);
+    }
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.breakpoints.iter().cloned().collect()
+    }
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        match self.watchpoints.iter().position(|&w| w == watchpoint) {
+            Some(i) => { self.watchpoints.remove(i); true },
+            None => false,
+        }
+    }
+    // Call when the machine jumps to another state, such as a loaded save
+    // state, so that stepping back does not cross the jump.
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+    pub fn pause(&mut self, chip8: &Chip8) {
+        self.mode = RunMode::Paused;
+        self.report_position(chip8);
+    }
+    fn resume(&mut self, mode: RunMode) {
+        if mode == RunMode::Running { self.history.clear(); }
+        self.mode = mode;
+        self.leaving_breakpoint = true;
+    }
+    pub fn execute(&mut self, command: Command, chip8: &mut Chip8) {
+        match command {
+            Command::Continue => self.resume(RunMode::Running),
+            Command::Pause => self.pause(chip8),
+            Command::Step => self.resume(RunMode::Step),
+            Command::Back => {
+                match self.history.pop() {
+                    Some(state) => match chip8.load_state(&state) {
+                        Ok(()) => self.pause(chip8),
+                        Err(err) => writeln!(self.output, "cannot go back: {}", err).unwrap(),
+                    },
+                    None => self.output.push_str("no earlier instruction to go back to\n"),
+                }
+            },
+            Command::Next => {
+                match chip8.peek_op() {
+                    Some(Chip8Op::Call(_)) => {
+                        self.resume(RunMode::StepOver { pc: chip8.pc() + 2, sp: chip8.sp() })
+                    },
+                    _ => self.resume(RunMode::Step),
+                }
+            },
+            Command::Finish => {
+                if chip8.sp() == 0 {
+                    self.output.push_str("not in a subroutine\n");
+                } else {
+                    self.resume(RunMode::Finish { sp: chip8.sp() });
+                }
+            },
+            Command::Break(addr) => {
+                self.add_breakpoint(addr);
+                writeln!(self.output, "breakpoint at {:03X}", addr).unwrap();
+            },
+            Command::ListBreakpoints => {
+                if self.breakpoints.is_empty() {
+                    self.output.push_str("no breakpoints\n");
+                }
+                for addr in self.breakpoints.iter() {
+                    writeln!(self.output, "breakpoint at {:03X}", addr).unwrap();
+                }
+            },
+            Command::Delete(addr) => {
+                if !self.remove_breakpoint(addr) {
+                    writeln!(self.output, "no breakpoint at {:03X}", addr).unwrap();
+                }
+            },
+            Command::Registers => self.report_registers(chip8),
+            Command::Memory(addr, len) => self.report_memory(chip8, addr, len),
+            Command::Watch(watchpoint) => {
+                self.add_watchpoint(watchpoint);
+                writeln!(self.output, "watchpoint {}: {}", self.watchpoints.len(), watchpoint.describe()).unwrap();
+            },
+            Command::ListWatchpoints => {
+                if self.watchpoints.is_empty() {
+                    self.output.push_str("no watchpoints\n");
+                }
+                for (i, watchpoint) in self.watchpoints.iter().enumerate() {
+                    writeln!(self.output, "watchpoint {}: {}", i + 1, watchpoint.describe()).unwrap();
+                }
+            },
+            Command::Unwatch(n) => {
+                if n >= 1 && n <= self.watchpoints.len() {
+                    self.watchpoints.remove(n - 1);
+                } else {
+                    writeln!(self.output, "no watchpoint {}", n).unwrap();
+                }
+            },
+            Command::Help => {
+                self.output.push_str(HELP);
+                self.output.push('\n');
+            },
+        }
+    }
+    // Returns false if the instruction at pc must not run yet.
+    pub fn before_cycle(&mut self, chip8: &Chip8) -> bool {
+        if self.mode == RunMode::Paused { return false; }
+        if self.leaving_breakpoint {
+            self.leaving_breakpoint = false;
+        } else if self.breakpoints.contains(&chip8.pc()) {
+            writeln!(self.output, "hit breakpoint at {:03X}", chip8.pc()).unwrap();
+            self.pause(chip8);
+            return false;
+        }
+        self.registers_before = chip8.registers();
+        if self.mode != RunMode::Running { self.history.push(chip8.save_state()); }
+        true
+    }
+    pub fn after_cycle(&mut self, chip8: &Chip8) {
+        if self.check_watchpoints(chip8) {
+            self.pause(chip8);
+            return;
+        }
+        let done = match self.mode {
+            RunMode::Paused | RunMode::Running => false,
+            RunMode::Step => true,
+            RunMode::StepOver { pc, sp } => chip8.pc() == pc && chip8.sp() == sp,
+            RunMode::Finish { sp } => chip8.sp() < sp,
+        };
+        if done { self.pause(chip8); }
+    }
+    // Reports every watchpoint the last instruction triggered.
+    fn check_watchpoints(&mut self, chip8: &Chip8) -> bool {
+        let mut hit = false;
+        let registers = chip8.registers();
+        for (i, watchpoint) in self.watchpoints.iter().enumerate() {
+            match *watchpoint {
+                Watchpoint::Memory { start, end, on_read, on_write } => {
+                    for access in chip8.memory_accesses() {
+                        let kind_matches = match access.kind {
+                            AccessKind::Read => on_read,
+                            AccessKind::Write => on_write,
+                        };
+                        if kind_matches && access.address >= start && access.address < end {
+                            let verb = if access.kind == AccessKind::Read { "read" } else { "write" };
+                            writeln!(self.output, "watchpoint {}: {} {:03X} = {:02X}",
+                                     i + 1, verb, access.address, access.value).unwrap();
+                            hit = true;
+                        }
+                    }
+                },
+                Watchpoint::Register(x) => {
+                    if registers[x] != self.registers_before[x] {
+                        writeln!(self.output, "watchpoint {}: V{:X} {:02X} -> {:02X}",
+                                 i + 1, x, self.registers_before[x], registers[x]).unwrap();
+                        hit = true;
+                    }
+                },
+            }
+        }
+        hit
+    }
+    fn report_position(&mut self, chip8: &Chip8) {
+        match chip8.peek_op() {
+            Some(op) => writeln!(self.output, "{:03X}: {:?}", chip8.pc(), op).unwrap(),
+            None => writeln!(self.output, "{:03X}: (invalid)", chip8.pc()).unwrap(),
+        }
+    }
+    fn report_registers(&mut self, chip8: &Chip8) {
+        for (i, value) in chip8.registers().iter().enumerate() {
+            write!(self.output, "V{:X}={:02X}{}", i, value, if i % 8 == 7 { "\n" } else { " " }).unwrap();
+        }
+        writeln!(self.output, "I={:03X} PC={:03X} SP={:X} DT={:02X} ST={:02X}",
+                 chip8.index(), chip8.pc(), chip8.sp(), chip8.delay_timer(), chip8.sound_timer()).unwrap();
+        let stack: Vec<String> = chip8.stack().iter().map(|addr| format!("{:03X}", addr)).collect();
+        writeln!(self.output, "stack: [{}]", stack.join(" ")).unwrap();
+        let keys: Vec<String> = chip8.keys().iter().enumerate()
+            .filter(|&(_, &pressed)| pressed)
+            .map(|(key, _)| format!("{:X}", key))
+            .collect();
+        writeln!(self.output, "keys: [{}]", keys.join(" ")).unwrap();
+    }
+    fn report_memory(&mut self, chip8: &Chip8, addr: usize, len: usize) {
+        let memory = chip8.memory();
+        if addr >= memory.len() {
+            writeln!(self.output, "address {:X} is out of range", addr).unwrap();
+            return;
+        }
+        let end = addr.saturating_add(len).min(memory.len());
+        for (row, bytes) in memory[addr..end].chunks(8).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            writeln!(self.output, "{:03X}: {}", addr + row * 8, hex.join(" ")).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    // 200: call 206; 202: V1 = 1; 204: jump 204; 206: V0 = 1; 208: return
+    fn program() -> Chip8 {
+        let mut chip8 = Chip8::new(Quirks::default());
+        chip8.load(vec![0x22, 0x06, 0x61, 0x01, 0x12, 0x04, 0x60, 0x01, 0x00, 0xEE]).unwrap();
+        chip8
+    }
+
+    fn run(debugger: &mut Debugger, chip8: &mut Chip8, max_cycles: usize) {
+        for _ in 0..max_cycles {
+            if !debugger.before_cycle(chip8) { break; }
+            chip8.emulate_cycle().unwrap();
+            debugger.after_cycle(chip8);
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("b 2a4"), Ok(Command::Break(0x2A4)));
+        assert_eq!(parse_command(" x 0x300 4 "), Ok(Command::Memory(0x300, 4)));
+        assert_eq!(parse_command("next"), Ok(Command::Next));
+        assert!(parse_command("b zz").is_err());
+    }
+    #[test]
+    fn starts_paused_and_steps() {
+        let mut debugger = Debugger::new();
+        let mut chip8 = program();
+        run(&mut debugger, &mut chip8, 10);
+        assert_eq!(chip8.pc(), 0x200);
+        debugger.execute(Command::Step, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        assert_eq!(chip8.pc(), 0x206);
+        assert!(debugger.is_paused());
+    }
+    #[test]
+    fn next_steps_over_call() {
+        let mut debugger = Debugger::new();
+        let mut chip8 = program();
+        debugger.execute(Command::Next, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        assert_eq!((chip8.pc(), chip8.sp()), (0x202, 0));
+    }
+    #[test]
+    fn finish_runs_to_return() {
+        let mut debugger = Debugger::new();
+        let mut chip8 = program();
+        debugger.execute(Command::Step, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        debugger.execute(Command::Finish, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        assert_eq!((chip8.pc(), chip8.registers()[0]), (0x202, 1));
+    }
+    #[test]
+    fn back_undoes_instructions() {
+        let mut debugger = Debugger::new();
+        let mut chip8 = program();
+        debugger.execute(Command::Next, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        assert_eq!(chip8.registers()[0], 1);
+        debugger.execute(Command::Back, &mut chip8);
+        assert_eq!((chip8.pc(), chip8.sp(), chip8.registers()[0]), (0x208, 1, 1));
+        debugger.execute(Command::Back, &mut chip8);
+        debugger.execute(Command::Back, &mut chip8);
+        assert_eq!((chip8.pc(), chip8.sp(), chip8.registers()[0]), (0x200, 0, 0));
+        debugger.take_output();
+        debugger.execute(Command::Back, &mut chip8);
+        assert_eq!(debugger.take_output().unwrap(), "no earlier instruction to go back to\n");
+        assert!(debugger.is_paused());
+    }
+    #[test]
+    fn examine_stops_at_the_end_of_memory() {
+        let mut debugger = Debugger::new();
+        let mut chip8 = program();
+        debugger.execute(parse_command("x fffe 18446744073709551615").unwrap(), &mut chip8);
+        assert_eq!(debugger.take_output().unwrap(), "FFFE: 00 00\n");
+        debugger.execute(parse_command("x ffffffffffffffff 1").unwrap(), &mut chip8);
+        assert_eq!(debugger.take_output().unwrap(), "address FFFFFFFFFFFFFFFF is out of range\n");
+    }
+    #[test]
+    fn parses_watchpoints() {
+        assert_eq!(parse_command("watch 300 3"), Ok(Command::Watch(
+            Watchpoint::Memory { start: 0x300, end: 0x303, on_read: false, on_write: true })));
+        assert_eq!(parse_command("rwatch 2a0"), Ok(Command::Watch(
+            Watchpoint::Memory { start: 0x2A0, end: 0x2A1, on_read: true, on_write: false })));
+        assert_eq!(parse_command("watch vF"), Ok(Command::Watch(Watchpoint::Register(0xF))));
+        assert!(parse_command("watch vG").is_err());
+        assert_eq!(parse_command("watch ffffffffffffffff 2"), Err("range out of bounds".to_string()));
+    }
+    #[test]
+    fn memory_watchpoint_stops_after_access() {
+        let mut debugger = Debugger::new();
+        let mut chip8 = Chip8::new(Quirks::default());
+        // 200: I = 300; 202: V0 = 2A; 204: store V0 at I; 206: jump 206
+        chip8.load(vec![0xA3, 0x00, 0x60, 0x2A, 0xF0, 0x55, 0x12, 0x06]).unwrap();
+        debugger.execute(Command::Watch(
+            Watchpoint::Memory { start: 0x300, end: 0x301, on_read: false, on_write: true }), &mut chip8);
+        debugger.execute(Command::Continue, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        assert!(debugger.is_paused());
+        assert_eq!(chip8.pc(), 0x206);
+        assert!(debugger.take_output().unwrap().contains("watchpoint 1: write 300 = 2A"));
+    }
+    #[test]
+    fn register_watchpoint_stops_on_change() {
+        let mut debugger = Debugger::new();
+        let mut chip8 = program();
+        debugger.execute(Command::Watch(Watchpoint::Register(1)), &mut chip8);
+        debugger.execute(Command::Continue, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        assert!(debugger.is_paused());
+        assert_eq!(chip8.pc(), 0x204);
+        assert!(debugger.take_output().unwrap().contains("V1 00 -> 01"));
+    }
+    #[test]
+    fn breakpoint_stops_and_resumes() {
+        let mut debugger = Debugger::new();
+        let mut chip8 = program();
+        debugger.execute(Command::Break(0x208), &mut chip8);
+        debugger.execute(Command::Continue, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        assert_eq!(chip8.pc(), 0x208);
+        assert!(debugger.take_output().unwrap().contains("hit breakpoint at 208"));
+        debugger.execute(Command::Continue, &mut chip8);
+        run(&mut debugger, &mut chip8, 2);
+        assert_eq!(chip8.pc(), 0x204);
+    }
+    #[test]
+    fn running_freely_keeps_no_history() {
+        let mut debugger = Debugger::new();
+        let mut chip8 = program();
+        debugger.execute(Command::Step, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        debugger.execute(Command::Break(0x204), &mut chip8);
+        debugger.execute(Command::Continue, &mut chip8);
+        run(&mut debugger, &mut chip8, 10);
+        assert_eq!(chip8.pc(), 0x204);
+        debugger.take_output();
+        // Going back would otherwise land before the step, skipping the run.
+        debugger.execute(Command::Back, &mut chip8);
+        assert_eq!(debugger.take_output().unwrap(), "no earlier instruction to go back to\n");
+        assert_eq!(chip8.pc(), 0x204);
+    }
+}
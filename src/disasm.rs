@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Write;
+
+use cpu::{Chip8Op, decode_opcode};
+
+// One decoded instruction, or a run of bytes that did not decode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    pub op: Option<Chip8Op>,
+}
+
+impl Instruction {
+    // The instruction in Chipper syntax, as used by games/SOURCES.
+    pub fn mnemonic(&self) -> String {
+        match self.op {
+            Some(op) => format_op(op, &self.bytes),
+            None => {
+                let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("#{:02X}", byte)).collect();
+                format!("DB {}", bytes.join(", "))
+            },
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let raw: String = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "{:03X}: {:<8} {}", self.address, raw, self.mnemonic())
+    }
+}
+
+fn format_op(op: Chip8Op, bytes: &[u8]) -> String {
+    match op {
+        Chip8Op::DisplayClear => "CLS".to_string(),
+        Chip8Op::Return => "RET".to_string(),
+        Chip8Op::Jump(addr) => format!("JP #{:03X}", addr),
+        Chip8Op::Call(addr) => format!("CALL #{:03X}", addr),
+        Chip8Op::CmpEqualConst(x, nn) => format!("SE V{:X}, #{:02X}", x, nn),
+        Chip8Op::CmpNotEqualConst(x, nn) => format!("SNE V{:X}, #{:02X}", x, nn),
+        Chip8Op::CmpEqualReg(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        Chip8Op::SetRegConst(x, nn) => format!("LD V{:X}, #{:02X}", x, nn),
+        Chip8Op::AddConstReg(x, nn) => format!("ADD V{:X}, #{:02X}", x, nn),
+        Chip8Op::SetRegReg(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Chip8Op::BitOpOr(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        Chip8Op::BitOpAnd(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Chip8Op::BitOpXor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Chip8Op::MathOpAdd(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Chip8Op::MathOpSub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        Chip8Op::BitOpShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        Chip8Op::MathOpSubNeg(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        Chip8Op::BitOpShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        Chip8Op::CmpNotEqualReg(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        Chip8Op::SetMemoryAddress(addr) => format!("LD I, #{:03X}", addr),
+        Chip8Op::JumpPlus(addr) => format!("JP V0, #{:03X}", addr),
+        Chip8Op::Random(x, nn) => format!("RND V{:X}, #{:02X}", x, nn),
+        Chip8Op::DrawSprite(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Chip8Op::KeyPressed(x) => format!("SKP V{:X}", x),
+        Chip8Op::KeyNotPressed(x) => format!("SKNP V{:X}", x),
+        Chip8Op::GetDelay(x) => format!("LD V{:X}, DT", x),
+        Chip8Op::GetKey(x) => format!("LD V{:X}, K", x),
+        Chip8Op::SetDelay(x) => format!("LD DT, V{:X}", x),
+        Chip8Op::SetSound(x) => format!("LD ST, V{:X}", x),
+        Chip8Op::AddMemoryAddress(x) => format!("ADD I, V{:X}", x),
+        Chip8Op::GetSprite(x) => format!("LD F, V{:X}", x),
+        Chip8Op::BinaryCoding(x) => format!("LD B, V{:X}", x),
+        Chip8Op::RegisterDump(x) => format!("LD [I], V{:X}", x),
+        Chip8Op::RegisterLoad(x) => format!("LD V{:X}, [I]", x),
+        Chip8Op::ScrollDown(n) => format!("SCD {}", n),
+        Chip8Op::ScrollRight => "SCR".to_string(),
+        Chip8Op::ScrollLeft => "SCL".to_string(),
+        Chip8Op::Exit => "EXIT".to_string(),
+        Chip8Op::LowRes => "LOW".to_string(),
+        Chip8Op::HighRes => "HIGH".to_string(),
+        Chip8Op::GetBigSprite(x) => format!("LD HF, V{:X}", x),
+        Chip8Op::SaveFlags(x) => format!("LD R, V{:X}", x),
+        Chip8Op::LoadFlags(x) => format!("LD V{:X}, R", x),
+        // Chipper predates XO-CHIP, so these mnemonics are our own.
+        Chip8Op::ScrollUp(n) => format!("SCU {}", n),
+        Chip8Op::LongSetMemoryAddress => {
+            let addr = ((bytes[2] as usize) << 8) | bytes[3] as usize;
+            format!("LD I, LONG #{:04X}", addr)
+        },
+        Chip8Op::SaveRange(x, y) => format!("SAVE V{:X}, V{:X}", x, y),
+        Chip8Op::LoadRange(x, y) => format!("LOAD V{:X}, V{:X}", x, y),
+        Chip8Op::SelectPlanes(n) => format!("PLANE {}", n),
+        Chip8Op::LoadAudio => "AUDIO".to_string(),
+        Chip8Op::SetPitch(x) => format!("PITCH V{:X}", x),
+    }
+}
+
+// Decodes the instruction at offset in bytes, which are loaded at origin.
+// Words that are not instructions become two-byte DB entries, and a lone
+// byte at the end a one-byte entry.
+pub(crate) fn decode_at(bytes: &[u8], offset: usize, origin: usize) -> Instruction {
+    let address = origin + offset;
+    if offset + 1 >= bytes.len() {
+        return Instruction { address, bytes: bytes[offset..].to_vec(), op: None };
+    }
+    let opcode = ((bytes[offset] as u16) << 8) | bytes[offset + 1] as u16;
+    match decode_opcode(opcode) {
+        Some(Chip8Op::LongSetMemoryAddress) if offset + 3 >= bytes.len() =>
+            Instruction { address, bytes: bytes[offset..offset + 2].to_vec(), op: None },
+        Some(Chip8Op::LongSetMemoryAddress) =>
+            Instruction { address, bytes: bytes[offset..offset + 4].to_vec(), op: Some(Chip8Op::LongSetMemoryAddress) },
+        op => Instruction { address, bytes: bytes[offset..offset + 2].to_vec(), op },
+    }
+}
+
+// Linear sweep over bytes loaded at origin. Sprite data mixed in with the
+// code comes out as whatever instructions it happens to look like.
+pub fn disassemble(bytes: &[u8], origin: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let instruction = decode_at(bytes, offset, origin);
+        offset += instruction.len();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// Where execution can go after an instruction, besides the targets of
+// jumps and calls.
+fn successors(bytes: &[u8], instruction: &Instruction, origin: usize) -> Vec<usize> {
+    let next = instruction.address + instruction.len();
+    match instruction.op {
+        Some(Chip8Op::Jump(addr)) | Some(Chip8Op::JumpPlus(addr)) => vec![addr],
+        Some(Chip8Op::Call(addr)) => vec![addr, next],
+        Some(Chip8Op::Return) | Some(Chip8Op::Exit) | None => vec![],
+        Some(Chip8Op::CmpEqualConst(..)) | Some(Chip8Op::CmpNotEqualConst(..)) |
+        Some(Chip8Op::CmpEqualReg(..)) | Some(Chip8Op::CmpNotEqualReg(..)) |
+        Some(Chip8Op::KeyPressed(_)) | Some(Chip8Op::KeyNotPressed(_)) => {
+            if next >= origin + bytes.len() { return vec![next]; }
+            let skipped = decode_at(bytes, next - origin, origin);
+            vec![next, next + skipped.len()]
+        },
+        Some(_) => vec![next],
+    }
+}
+
+// A ROM split into the code reachable from its first byte and the data
+// around it. Data bytes are single-byte Instructions with no op.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub origin: usize,
+    pub instructions: Vec<Instruction>,
+    // Addresses that jumps, calls and LD I refer to.
+    pub code_targets: BTreeSet<usize>,
+    pub data_targets: BTreeSet<usize>,
+}
+
+// Follows jumps, calls, skips and returns from origin, so sprites and other
+// data stored between routines are not mistaken for instructions. Computed
+// jumps (JP V0) are only followed to their base address.
+pub fn trace(bytes: &[u8], origin: usize) -> Listing {
+    let mut code: BTreeMap<usize, Instruction> = BTreeMap::new();
+    let mut covered = vec![false; bytes.len()];
+    let mut code_targets = BTreeSet::new();
+    let mut data_targets = BTreeSet::new();
+    let mut pending = vec![origin];
+    while let Some(address) = pending.pop() {
+        if address < origin || address >= origin + bytes.len() || code.contains_key(&address) {
+            continue;
+        }
+        let instruction = decode_at(bytes, address - origin, origin);
+        let offset = address - origin;
+        // Stop at data, and at paths that run into the middle of code
+        // already decoded.
+        if instruction.op.is_none() || covered[offset..offset + instruction.len()].iter().any(|&c| c) {
+            continue;
+        }
+        for c in covered[offset..offset + instruction.len()].iter_mut() {
+            *c = true;
+        }
+        match instruction.op {
+            Some(Chip8Op::Jump(addr)) | Some(Chip8Op::Call(addr)) | Some(Chip8Op::JumpPlus(addr)) => {
+                code_targets.insert(addr);
+            },
+            Some(Chip8Op::SetMemoryAddress(addr)) => { data_targets.insert(addr); },
+            Some(Chip8Op::LongSetMemoryAddress) => {
+                data_targets.insert(((instruction.bytes[2] as usize) << 8) | instruction.bytes[3] as usize);
+            },
+            _ => {},
+        }
+        pending.extend(successors(bytes, &instruction, origin));
+        code.insert(address, instruction);
+    }
+
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let instruction = match code.remove(&(origin + offset)) {
+            Some(instruction) => instruction,
+            None => Instruction { address: origin + offset, bytes: vec![bytes[offset]], op: None },
+        };
+        offset += instruction.len();
+        instructions.push(instruction);
+    }
+    Listing { origin, instructions, code_targets, data_targets }
+}
+
+fn sprite_row(byte: u8) -> String {
+    (0..8).map(|bit| if byte & (0x80 >> bit) != 0 { '1' } else { '.' }).collect()
+}
+
+impl Listing {
+    // Chipper source that assembles back to the original bytes. Targets
+    // inside the ROM get labels: SUB_ for calls, L_ for other code and
+    // DATA_ for LD I, written as label+offset if they point into the middle
+    // of an instruction.
+    pub fn to_source(&self) -> String {
+        let end = self.origin + self.instructions.iter().map(|i| i.len()).sum::<usize>();
+        let starts: BTreeSet<usize> = self.instructions.iter().map(|i| i.address).collect();
+        let calls: BTreeSet<usize> = self.instructions.iter().filter_map(|i| match i.op {
+            Some(Chip8Op::Call(addr)) => Some(addr),
+            _ => None,
+        }).collect();
+        let mut labels: BTreeMap<usize, String> = BTreeMap::new();
+        let mut references: BTreeMap<usize, String> = BTreeMap::new();
+        for &target in self.code_targets.iter().chain(self.data_targets.iter()) {
+            if target < self.origin || target >= end { continue; }
+            let start = *starts.range(..target + 1).next_back().unwrap();
+            let name = labels.entry(start).or_insert_with(|| {
+                let prefix = if calls.contains(&start) {
+                    "SUB"
+                } else if self.code_targets.contains(&start) {
+                    "L"
+                } else {
+                    "DATA"
+                };
+                format!("{}_{:03X}", prefix, start)
+            }).clone();
+            let reference = if target == start { name } else { format!("{}+{}", name, target - start) };
+            references.insert(target, reference);
+        }
+        let operand = |addr: usize, digits: usize| match references.get(&addr) {
+            Some(reference) => reference.clone(),
+            None => format!("#{:0width$X}", addr, width = digits),
+        };
+
+        // Code can start at odd addresses after an odd run of data.
+        let mut source = String::from("    ALIGN OFF\n");
+        if self.origin != 0x200 {
+            writeln!(source, "    ORG #{:03X}", self.origin).unwrap();
+        }
+        for instruction in &self.instructions {
+            if let Some(label) = labels.get(&instruction.address) {
+                writeln!(source, "{}:", label).unwrap();
+            }
+            let line = match instruction.op {
+                Some(Chip8Op::Jump(addr)) => format!("JP {}", operand(addr, 3)),
+                Some(Chip8Op::Call(addr)) => format!("CALL {}", operand(addr, 3)),
+                Some(Chip8Op::JumpPlus(addr)) => format!("JP V0, {}", operand(addr, 3)),
+                Some(Chip8Op::SetMemoryAddress(addr)) => format!("LD I, {}", operand(addr, 3)),
+                Some(Chip8Op::LongSetMemoryAddress) => {
+                    let addr = ((instruction.bytes[2] as usize) << 8) | instruction.bytes[3] as usize;
+                    format!("LD I, LONG {}", operand(addr, 4))
+                },
+                Some(_) => instruction.mnemonic(),
+                None => format!("{:<15} ; {}", instruction.mnemonic(), sprite_row(instruction.bytes[0])),
+            };
+            writeln!(source, "    {}", line).unwrap();
+        }
+        source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chipper_mnemonics() {
+        let program = [0x00, 0xE0, 0x6A, 0x02, 0xA2, 0xEA, 0xDA, 0xB6, 0xF3, 0x65, 0x12, 0x00];
+        let mnemonics: Vec<String> = disassemble(&program, 0x200).iter().map(|i| i.mnemonic()).collect();
+        assert_eq!(mnemonics, ["CLS", "LD VA, #02", "LD I, #2EA", "DRW VA, VB, 6", "LD V3, [I]", "JP #200"]);
+    }
+    #[test]
+    fn undecodable_bytes_are_data() {
+        let instructions = disassemble(&[0xFF, 0xFF, 0xF0, 0x00, 0x12, 0x34, 0x80], 0x200);
+        assert_eq!(instructions[0].mnemonic(), "DB #FF, #FF");
+        assert_eq!(instructions[1].mnemonic(), "LD I, LONG #1234");
+        assert_eq!(instructions[2].address, 0x206);
+        assert_eq!(instructions[2].mnemonic(), "DB #80");
+        assert_eq!(instructions[1].to_string(), "202: F0001234 LD I, LONG #1234");
+    }
+    #[test]
+    fn trace_separates_code_from_data() {
+        // 200: LD I, 208; 202: CALL 20A; 204: JP 204; 206: sprite
+        // 208: sprite; 20A: DRW V0, V0, 2; 20C: RET
+        let program = [0xA2, 0x08, 0x22, 0x0A, 0x12, 0x04, 0xFF, 0xFF, 0x3C, 0x42, 0xD0, 0x02, 0x00, 0xEE];
+        let listing = trace(&program, 0x200);
+        let ops: Vec<bool> = listing.instructions.iter().map(|i| i.op.is_some()).collect();
+        assert_eq!(ops, [true, true, true, false, false, false, false, true, true]);
+        assert_eq!(listing.to_source(), "    ALIGN OFF
+    LD I, DATA_208
+    CALL SUB_20A
+L_204:
+    JP L_204
+    DB #FF          ; 11111111
+    DB #FF          ; 11111111
+DATA_208:
+    DB #3C          ; ..1111..
+    DB #42          ; .1....1.
+SUB_20A:
+    DRW V0, V0, 2
+    RET
+");
+    }
+    #[test]
+    fn skips_reach_both_paths() {
+        // 200: SE V0, 0; 202: JP 206; 204: EXIT; 206: RET
+        let listing = trace(&[0x30, 0x00, 0x12, 0x06, 0x00, 0xFD, 0x00, 0xEE], 0x200);
+        assert!(listing.instructions.iter().all(|i| i.op.is_some()));
+    }
+    #[test]
+    fn references_into_an_instruction_use_an_offset() {
+        // 200: LD I, 203; 202: LD V0, 5; 204: JP 204
+        let source = trace(&[0xA2, 0x03, 0x60, 0x05, 0x12, 0x04], 0x200).to_source();
+        assert!(source.starts_with("    ALIGN OFF\n    LD I, DATA_202+1\nDATA_202:\n    LD V0, #05\n"));
+    }
+}
@@ -0,0 +1,133 @@
+// Framebuffer whose size follows the current resolution mode: 64x32 in
+// low-res and 128x64 in high-res. Each pixel holds one bit per XO-CHIP
+// bitplane, so a pixel value is a colour index from 0 to 3.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Display {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Display {
+    pub fn new(width: usize, height: usize) -> Self {
+        Display { width,
+                  height,
+                  pixels: vec![0; width * height],
+        }
+    }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+    // One line per row, with '.' for unlit pixels, '#' and '+' for pixels lit
+    // on the first or second plane only and '@' for both.
+    pub fn to_text(&self) -> String {
+        let mut text = String::with_capacity((self.width + 1) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            text.extend(row.iter().map(|&color| ['.', '#', '+', '@'][color as usize & 3]));
+            text.push('\n');
+        }
+        text
+    }
+    // True if the pixel is lit on any plane.
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.color(x, y) != 0
+    }
+    pub fn color(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+    // XORs a pixel on the given plane and returns true if it was already lit.
+    pub(crate) fn flip(&mut self, x: usize, y: usize, plane: u8) -> bool {
+        let index = y * self.width + x;
+        let was_lit = self.pixels[index] & plane != 0;
+        self.pixels[index] ^= plane;
+        was_lit
+    }
+    pub(crate) fn clear(&mut self, planes: u8) {
+        for pixel in self.pixels.iter_mut() {
+            *pixel &= !planes;
+        }
+    }
+    // Rebuilds a framebuffer from a save state. Returns None if the pixel
+    // count does not match the size, or a pixel is lit on a plane that does
+    // not exist.
+    pub(crate) fn from_pixels(width: usize, height: usize, pixels: Vec<u8>) -> Option<Self> {
+        if pixels.len() != width * height { return None; }
+        if pixels.iter().any(|&pixel| pixel > 0x3) { return None; }
+        Some(Display { width, height, pixels })
+    }
+    pub(crate) fn resize(&mut self, width: usize, height: usize) {
+        *self = Display::new(width, height);
+    }
+    pub(crate) fn scroll_down(&mut self, planes: u8, rows: usize) {
+        self.shift(planes, 0, rows as isize);
+    }
+    pub(crate) fn scroll_up(&mut self, planes: u8, rows: usize) {
+        self.shift(planes, 0, -(rows as isize));
+    }
+    pub(crate) fn scroll_right(&mut self, planes: u8, cols: usize) {
+        self.shift(planes, cols as isize, 0);
+    }
+    pub(crate) fn scroll_left(&mut self, planes: u8, cols: usize) {
+        self.shift(planes, -(cols as isize), 0);
+    }
+    // Moves the selected planes by (dx, dy), filling the uncovered edge with
+    // unlit pixels and leaving the other planes in place.
+    fn shift(&mut self, planes: u8, dx: isize, dy: isize) {
+        let old = self.pixels.clone();
+        let (width, height) = (self.width as isize, self.height as isize);
+        for y in 0..height {
+            for x in 0..width {
+                let (sx, sy) = (x - dx, y - dy);
+                let moved = if sx >= 0 && sx < width && sy >= 0 && sy < height {
+                    old[(sy * width + sx) as usize]
+                } else {
+                    0
+                };
+                let index = (y * width + x) as usize;
+                self.pixels[index] = (old[index] & !planes) | (moved & planes);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scroll_down_clears_top_rows() {
+        let mut display = Display::new(4, 3);
+        display.flip(1, 0, 1);
+        display.scroll_down(1, 2);
+        assert!(!display.get(1, 0));
+        assert!(display.get(1, 2));
+    }
+    #[test]
+    fn scroll_sideways() {
+        let mut display = Display::new(8, 2);
+        display.flip(0, 1, 1);
+        display.scroll_right(1, 4);
+        assert!(display.get(4, 1));
+        display.scroll_left(1, 4);
+        assert!(display.get(0, 1));
+        display.scroll_left(1, 4);
+        assert_eq!(display.pixels().iter().filter(|&&p| p != 0).count(), 0);
+    }
+    #[test]
+    fn scroll_only_moves_selected_planes() {
+        let mut display = Display::new(4, 4);
+        display.flip(0, 0, 1);
+        display.flip(0, 0, 2);
+        display.scroll_down(2, 1);
+        assert_eq!(display.color(0, 0), 1);
+        assert_eq!(display.color(0, 1), 2);
+        display.scroll_up(2, 1);
+        assert_eq!(display.color(0, 0), 3);
+    }
+}
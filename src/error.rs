@@ -0,0 +1,39 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chip8Error {
+    InvalidOpcode { opcode: u16, address: usize },
+    StackOverflow { address: usize },
+    StackUnderflow { address: usize },
+    MemoryOutOfBounds { address: usize },
+    PcOutOfBounds { pc: usize },
+    RomTooLarge { size: usize },
+    BadState { reason: &'static str },
+    BadMovie { reason: &'static str },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Chip8Error::InvalidOpcode { opcode, address } =>
+                write!(f, "invalid opcode {:04X} at {:03X}", opcode, address),
+            Chip8Error::StackOverflow { address } =>
+                write!(f, "stack overflow at {:03X}", address),
+            Chip8Error::StackUnderflow { address } =>
+                write!(f, "stack underflow at {:03X}", address),
+            Chip8Error::MemoryOutOfBounds { address } =>
+                write!(f, "memory access out of bounds at {:X}", address),
+            Chip8Error::PcOutOfBounds { pc } =>
+                write!(f, "program counter ran off the end of memory at {:X}", pc),
+            Chip8Error::RomTooLarge { size } =>
+                write!(f, "rom of {} bytes does not fit in memory", size),
+            Chip8Error::BadState { reason } =>
+                write!(f, "bad save state: {}", reason),
+            Chip8Error::BadMovie { reason } =>
+                write!(f, "bad movie: {}", reason),
+        }
+    }
+}
+
+impl Error for Chip8Error {}
@@ -1,8 +1,60 @@
-use {DISPSIZE, NUM_KEYS};
+use display::Display;
+use sound::Tone;
+use NUM_KEYS;
+
+// Emulator controls outside the CHIP-8 keypad. Slots are numbered from 1.
+// Rewind is reported once per frame for as long as it is held.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    SaveState(u8),
+    LoadState(u8),
+    Rewind,
+    Screenshot,
+}
 
 pub trait Frontend {
-    fn draw_graphics(&mut self, display: [bool; DISPSIZE]);
-    fn beep(&self);
+    fn draw_graphics(&mut self, display: &Display);
+    fn start_tone(&mut self, tone: Tone);
+    fn stop_tone(&mut self);
     fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]>;
     fn get_key(&mut self) -> Option<u8>;
-}
\ No newline at end of file
+    // A line typed at the debugger prompt, if one is ready.
+    fn poll_debug_command(&mut self) -> Option<String> {
+        None
+    }
+    // A hotkey pressed since the last call, if any. Front ends pick these up
+    // in check_keys.
+    fn poll_hotkey(&mut self) -> Option<Hotkey> {
+        None
+    }
+    fn print_debug(&mut self, text: &str) {
+        print!("{}", text);
+    }
+}
+// Lets main pick a front end at run time and wrap it.
+impl<F: Frontend + ?Sized> Frontend for Box<F> {
+    fn draw_graphics(&mut self, display: &Display) {
+        (**self).draw_graphics(display);
+    }
+    fn start_tone(&mut self, tone: Tone) {
+        (**self).start_tone(tone);
+    }
+    fn stop_tone(&mut self) {
+        (**self).stop_tone();
+    }
+    fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]> {
+        (**self).check_keys()
+    }
+    fn get_key(&mut self) -> Option<u8> {
+        (**self).get_key()
+    }
+    fn poll_debug_command(&mut self) -> Option<String> {
+        (**self).poll_debug_command()
+    }
+    fn poll_hotkey(&mut self) -> Option<Hotkey> {
+        (**self).poll_hotkey()
+    }
+    fn print_debug(&mut self, text: &str) {
+        (**self).print_debug(text);
+    }
+}
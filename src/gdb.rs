@@ -0,0 +1,494 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, SocketAddr};
+
+use cpu::Chip8;
+use debugger::{Command, Debugger, Watchpoint};
+use NUM_REGS;
+
+// Register numbers in g/G/p/P packets. V0-VF come first, one byte each;
+// I and PC are two bytes, sent big-endian like everything else on CHIP-8.
+const REG_I: usize = NUM_REGS;
+const REG_PC: usize = NUM_REGS + 1;
+const REG_SP: usize = NUM_REGS + 2;
+const REG_DT: usize = NUM_REGS + 3;
+const REG_ST: usize = NUM_REGS + 4;
+const NUM_GDB_REGS: usize = NUM_REGS + 5;
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\"><feature name=\"org.rschip8.core\">\
+<reg name=\"v0\" bitsize=\"8\"/><reg name=\"v1\" bitsize=\"8\"/>\
+<reg name=\"v2\" bitsize=\"8\"/><reg name=\"v3\" bitsize=\"8\"/>\
+<reg name=\"v4\" bitsize=\"8\"/><reg name=\"v5\" bitsize=\"8\"/>\
+<reg name=\"v6\" bitsize=\"8\"/><reg name=\"v7\" bitsize=\"8\"/>\
+<reg name=\"v8\" bitsize=\"8\"/><reg name=\"v9\" bitsize=\"8\"/>\
+<reg name=\"va\" bitsize=\"8\"/><reg name=\"vb\" bitsize=\"8\"/>\
+<reg name=\"vc\" bitsize=\"8\"/><reg name=\"vd\" bitsize=\"8\"/>\
+<reg name=\"ve\" bitsize=\"8\"/><reg name=\"vf\" bitsize=\"8\"/>\
+<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>\
+<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+<reg name=\"sp\" bitsize=\"8\"/>\
+<reg name=\"dt\" bitsize=\"8\"/><reg name=\"st\" bitsize=\"8\"/>\
+</feature></target>";
+
+fn reg_size(reg: usize) -> usize {
+    if reg == REG_I || reg == REG_PC { 2 } else { 1 }
+}
+
+fn read_reg(chip8: &Chip8, reg: usize) -> usize {
+    match reg {
+        REG_I => chip8.index(),
+        REG_PC => chip8.pc(),
+        REG_SP => chip8.sp(),
+        REG_DT => chip8.delay_timer() as usize,
+        REG_ST => chip8.sound_timer() as usize,
+        x => chip8.registers()[x] as usize,
+    }
+}
+
+fn write_reg(chip8: &mut Chip8, reg: usize, value: usize) {
+    match reg {
+        REG_I => chip8.set_index(value),
+        REG_PC => chip8.set_pc(value),
+        REG_SP => chip8.set_sp(value),
+        REG_DT => chip8.set_delay_timer(value as u8),
+        REG_ST => chip8.set_sound_timer(value as u8),
+        x => chip8.set_register(x, value as u8),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) { return None; }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn encode_reg(chip8: &Chip8, reg: usize) -> String {
+    let value = read_reg(chip8, reg);
+    if reg_size(reg) == 2 {
+        to_hex(&[(value >> 8) as u8, value as u8])
+    } else {
+        to_hex(&[value as u8])
+    }
+}
+
+fn decode_reg(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |value, &byte| (value << 8) | byte as usize)
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte))
+}
+
+// Serves the GDB remote serial protocol on a TCP socket. Execution control
+// goes through a Debugger, so the game loop treats a connected client just
+// like the built-in debugger prompt. poll never blocks; call it once a frame.
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    incoming: Vec<u8>,
+    no_ack: bool,
+    // Set after c or s until the debugger pauses and a stop reply is sent.
+    running: bool,
+}
+
+impl GdbServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbServer { listener,
+                       client: None,
+                       incoming: Vec::new(),
+                       no_ack: false,
+                       running: false,
+        })
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+    // Accepts a client, handles every packet that has arrived and reports
+    // when a continue or step has finished. A client that goes away is
+    // dropped and the game keeps running.
+    pub fn poll(&mut self, chip8: &mut Chip8, debugger: &mut Debugger) {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(true).is_err() { return; }
+                    self.client = Some(stream);
+                    self.incoming.clear();
+                    self.no_ack = false;
+                    self.running = false;
+                    debugger.pause(chip8);
+                },
+                Err(_) => return,
+            }
+        }
+        if self.receive().is_err() {
+            self.disconnect(chip8, debugger);
+            return;
+        }
+        while let Some(packet) = self.next_packet(chip8, debugger) {
+            let reply = self.handle(&packet, chip8, debugger);
+            if let Some(reply) = reply {
+                self.send(&reply);
+            }
+        }
+        if self.running && debugger.is_paused() {
+            self.running = false;
+            self.send("S05");
+        }
+    }
+    // The game carries on without the client.
+    fn disconnect(&mut self, chip8: &mut Chip8, debugger: &mut Debugger) {
+        self.client = None;
+        self.running = false;
+        if debugger.is_paused() {
+            debugger.execute(Command::Continue, chip8);
+        }
+    }
+    fn receive(&mut self) -> io::Result<()> {
+        let client = match self.client {
+            Some(ref mut client) => client,
+            None => return Ok(()),
+        };
+        let mut buffer = [0; 1024];
+        loop {
+            match client.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.incoming.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    // Pulls the next complete $data#cs packet out of the input, acknowledging
+    // it. A ^C byte between packets interrupts a running program.
+    fn next_packet(&mut self, chip8: &Chip8, debugger: &mut Debugger) -> Option<String> {
+        loop {
+            match self.incoming.first() {
+                None => return None,
+                Some(&b'$') => {},
+                Some(&0x03) => {
+                    self.incoming.remove(0);
+                    debugger.pause(chip8);
+                    continue;
+                },
+                Some(_) => {
+                    // Acks and line noise.
+                    self.incoming.remove(0);
+                    continue;
+                },
+            }
+            let end = self.incoming.iter().position(|&byte| byte == b'#')?;
+            if self.incoming.len() < end + 3 { return None; }
+            let packet: Vec<u8> = self.incoming.drain(..end + 3).collect();
+            let data = String::from_utf8_lossy(&packet[1..end]).into_owned();
+            let sum = String::from_utf8_lossy(&packet[end + 1..]).into_owned();
+            if !self.no_ack {
+                if u8::from_str_radix(&sum, 16).ok() == Some(checksum(&data)) {
+                    self.send_raw(b"+");
+                } else {
+                    self.send_raw(b"-");
+                    continue;
+                }
+            }
+            return Some(data);
+        }
+    }
+    fn send(&mut self, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        self.send_raw(packet.as_bytes());
+    }
+    fn send_raw(&mut self, bytes: &[u8]) {
+        if let Some(ref mut client) = self.client {
+            // Replies are short; block briefly rather than buffer them.
+            let _ = client.set_nonblocking(false);
+            let _ = client.write_all(bytes);
+            let _ = client.set_nonblocking(true);
+        }
+    }
+    // Returns the reply to a packet, or None if the reply comes later.
+    fn handle(&mut self, packet: &str, chip8: &mut Chip8, debugger: &mut Debugger) -> Option<String> {
+        let (kind, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match kind {
+            "?" => "S05".to_string(),
+            "g" => (0..NUM_GDB_REGS).map(|reg| encode_reg(chip8, reg)).collect(),
+            "G" => match from_hex(args) {
+                Some(ref bytes) if bytes.len() >= NUM_REGS + 7 => {
+                    let mut offset = 0;
+                    for reg in 0..NUM_GDB_REGS {
+                        let size = reg_size(reg);
+                        write_reg(chip8, reg, decode_reg(&bytes[offset..offset + size]));
+                        offset += size;
+                    }
+                    "OK".to_string()
+                },
+                _ => "E01".to_string(),
+            },
+            "p" => match parse_hex(args) {
+                Some(reg) if reg < NUM_GDB_REGS => encode_reg(chip8, reg),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let reg = parts.next().and_then(parse_hex);
+                let bytes = parts.next().and_then(from_hex);
+                match (reg, bytes) {
+                    (Some(reg), Some(ref bytes)) if reg < NUM_GDB_REGS && bytes.len() == reg_size(reg) => {
+                        write_reg(chip8, reg, decode_reg(bytes));
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "m" => match self.parse_range(args, chip8) {
+                Some((addr, len)) => to_hex(&chip8.memory()[addr..addr + len]),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(|range| self.parse_range(range, chip8));
+                let bytes = parts.next().and_then(from_hex);
+                match (range, bytes) {
+                    (Some((addr, len)), Some(ref bytes)) if bytes.len() == len => {
+                        chip8.memory_mut()[addr..addr + len].copy_from_slice(bytes);
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "Z" | "z" => self.handle_breakpoint(kind == "Z", args, debugger),
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    chip8.set_pc(addr);
+                }
+                let command = if kind == "c" { Command::Continue } else { Command::Step };
+                debugger.execute(command, chip8);
+                self.running = true;
+                return None;
+            },
+            "D" => {
+                self.send("OK");
+                self.disconnect(chip8, debugger);
+                return None;
+            },
+            "k" => {
+                chip8.exit();
+                self.client = None;
+                return None;
+            },
+            "q" if args.starts_with("Supported") => "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string(),
+            "q" if args.starts_with("Xfer:features:read:target.xml:") => {
+                let window = &args["Xfer:features:read:target.xml:".len()..];
+                let mut parts = window.splitn(2, ',');
+                match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+                    (Some(offset), Some(len)) => match offset.checked_add(len) {
+                        Some(end) => {
+                            let start = offset.min(TARGET_XML.len());
+                            let end = end.min(TARGET_XML.len());
+                            let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                            format!("{}{}", more, &TARGET_XML[start..end])
+                        },
+                        None => "E01".to_string(),
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "q" if args == "Attached" => "1".to_string(),
+            "Q" if args == "StartNoAckMode" => {
+                self.send("OK");
+                self.no_ack = true;
+                return None;
+            },
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+    fn parse_range(&self, args: &str, chip8: &Chip8) -> Option<(usize, usize)> {
+        let mut parts = args.splitn(2, ',');
+        let addr = parts.next().and_then(parse_hex)?;
+        let len = parts.next().and_then(parse_hex)?;
+        if addr.checked_add(len)? > chip8.memory().len() { return None; }
+        Some((addr, len))
+    }
+    // Z0/Z1 are breakpoints; Z2, Z3 and Z4 are write, read and access
+    // watchpoints over LEN bytes.
+    fn handle_breakpoint(&self, insert: bool, args: &str, debugger: &mut Debugger) -> String {
+        let fields: Vec<Option<usize>> = args.split(',').map(parse_hex).collect();
+        let (kind, addr, len) = match fields.as_slice() {
+            [Some(kind), Some(addr), Some(len)] => (*kind, *addr, *len),
+            _ => return "E01".to_string(),
+        };
+        let end = match addr.checked_add(len.max(1)) {
+            Some(end) => end,
+            None => return "E01".to_string(),
+        };
+        let watch = |on_read, on_write| Watchpoint::Memory { start: addr, end, on_read, on_write };
+        let watchpoint = match kind {
+            0 | 1 => {
+                if insert {
+                    debugger.add_breakpoint(addr);
+                } else {
+                    debugger.remove_breakpoint(addr);
+                }
+                return "OK".to_string();
+            },
+            2 => watch(false, true),
+            3 => watch(true, false),
+            4 => watch(true, true),
+            _ => return String::new(),
+        };
+        if insert {
+            debugger.add_watchpoint(watchpoint);
+        } else {
+            debugger.remove_watchpoint(watchpoint);
+        }
+        "OK".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time};
+    use quirks::Quirks;
+
+    struct Session {
+        server: GdbServer,
+        client: TcpStream,
+        chip8: Chip8,
+        debugger: Debugger,
+    }
+
+    impl Session {
+        fn new() -> Self {
+            let server = GdbServer::bind("127.0.0.1:0").unwrap();
+            let client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+            client.set_read_timeout(Some(time::Duration::from_millis(10))).unwrap();
+            let mut chip8 = Chip8::new(Quirks::default());
+            // 200: V1 = 1; 202: V1 += 1; 204: jump 202
+            chip8.load(vec![0x61, 0x01, 0x71, 0x01, 0x12, 0x02]).unwrap();
+            let mut debugger = Debugger::new();
+            debugger.execute(Command::Continue, &mut chip8);
+            Session { server, client, chip8, debugger }
+        }
+        fn poll(&mut self) {
+            self.server.poll(&mut self.chip8, &mut self.debugger);
+        }
+        // Stands in for the game loop between polls.
+        fn run(&mut self, cycles: usize) {
+            for _ in 0..cycles {
+                if !self.debugger.before_cycle(&self.chip8) { break; }
+                self.chip8.emulate_cycle().unwrap();
+                self.debugger.after_cycle(&self.chip8);
+            }
+        }
+        fn send(&mut self, data: &str) {
+            write!(self.client, "${}#{:02x}", data, checksum(data)).unwrap();
+        }
+        // Polls until one reply packet arrives and returns its data.
+        fn reply(&mut self) -> String {
+            let mut received = Vec::new();
+            for _ in 0..200 {
+                self.poll();
+                let mut buffer = [0; 1024];
+                if let Ok(n) = self.client.read(&mut buffer) {
+                    received.extend_from_slice(&buffer[..n]);
+                }
+                while received.first() == Some(&b'+') { received.remove(0); }
+                if let Some(end) = received.iter().position(|&byte| byte == b'#') {
+                    if received.len() >= end + 3 {
+                        return String::from_utf8_lossy(&received[1..end]).into_owned();
+                    }
+                }
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            panic!("no reply");
+        }
+        // Sends c or s and waits until the server has let the program run.
+        fn resume(&mut self, data: &str) {
+            self.send(data);
+            for _ in 0..200 {
+                self.poll();
+                if !self.debugger.is_paused() { return; }
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            panic!("program did not resume");
+        }
+        fn exchange(&mut self, data: &str) -> String {
+            self.send(data);
+            self.reply()
+        }
+    }
+
+    #[test]
+    fn connecting_pauses_and_reports_registers() {
+        let mut session = Session::new();
+        session.poll();
+        assert!(session.server.is_connected());
+        assert!(session.debugger.is_paused());
+        assert_eq!(session.exchange("?"), "S05");
+        let registers = session.exchange("g");
+        assert_eq!(registers.len(), (NUM_REGS + 7) * 2);
+        // PC is the 18th register, after V0-VF and the two-byte I.
+        assert_eq!(&registers[36..40], "0200");
+        assert_eq!(session.exchange("p11"), "0200");
+    }
+    #[test]
+    fn reads_and_writes_memory_and_registers() {
+        let mut session = Session::new();
+        assert_eq!(session.exchange("m200,4"), "61017101");
+        assert_eq!(session.exchange("M300,2:abcd"), "OK");
+        assert_eq!(&session.chip8.memory()[0x300..0x302], &[0xAB, 0xCD]);
+        assert_eq!(session.exchange("P3=7f"), "OK");
+        assert_eq!(session.chip8.registers()[3], 0x7F);
+        assert_eq!(session.exchange("P10=0345"), "OK");
+        assert_eq!(session.chip8.index(), 0x345);
+        assert_eq!(session.exchange("mffff,2"), "E01");
+    }
+    #[test]
+    fn ranges_that_overflow_are_refused() {
+        let mut session = Session::new();
+        assert_eq!(session.exchange("qXfer:features:read:target.xml:ffffffffffffffff,2"), "E01");
+        assert_eq!(session.exchange("Z2,ffffffffffffffff,2"), "E01");
+        assert!(session.exchange("qXfer:features:read:target.xml:0,10").starts_with('m'));
+    }
+    #[test]
+    fn step_and_breakpoint() {
+        let mut session = Session::new();
+        session.poll();
+        session.resume("s");
+        session.run(10);
+        assert_eq!(session.reply(), "S05");
+        assert_eq!(session.chip8.pc(), 0x202);
+        assert_eq!(session.exchange("Z0,204,2"), "OK");
+        session.resume("c");
+        session.run(10);
+        assert_eq!(session.reply(), "S05");
+        assert_eq!(session.chip8.pc(), 0x204);
+        assert_eq!(session.chip8.registers()[1], 2);
+    }
+    #[test]
+    fn interrupt_stops_a_running_program() {
+        let mut session = Session::new();
+        session.poll();
+        session.resume("c");
+        session.run(10);
+        assert!(!session.debugger.is_paused());
+        session.client.write_all(&[0x03]).unwrap();
+        assert_eq!(session.reply(), "S05");
+        assert!(session.debugger.is_paused());
+    }
+}
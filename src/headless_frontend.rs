@@ -0,0 +1,193 @@
+use cpu::Chip8;
+use error::Chip8Error;
+use frontend::Frontend;
+use display::Display;
+use sound::{SoundEvent, Tone};
+
+use {DISPWIDTH, DISPHEIGHT, NUM_KEYS};
+
+// A key held down from first_frame to last_frame inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyPress {
+    pub key: u8,
+    pub first_frame: u64,
+    pub last_frame: u64,
+}
+
+// Parses a comma separated list of FRAME:KEY or FIRST-LAST:KEY entries, with
+// frames in decimal and keys as a hex digit, e.g. "10:5,30-45:A".
+pub fn parse_key_script(script: &str) -> Result<Vec<KeyPress>, String> {
+    let mut presses = Vec::new();
+    for entry in script.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (frames, key) = match entry.split_once(':') {
+            Some(parts) => parts,
+            None => return Err(format!("expected FRAME:KEY, got '{}'", entry)),
+        };
+        let key = match u8::from_str_radix(key, 16) {
+            Ok(key) if (key as usize) < NUM_KEYS => key,
+            _ => return Err(format!("bad key '{}'", key)),
+        };
+        let parse_frame = |frame: &str| frame.parse::<u64>().map_err(|_| format!("bad frame '{}'", frame));
+        let (first_frame, last_frame) = match frames.split_once('-') {
+            Some((first, last)) => (parse_frame(first)?, parse_frame(last)?),
+            None => (parse_frame(frames)?, parse_frame(frames)?),
+        };
+        if last_frame < first_frame {
+            return Err(format!("frames run backwards in '{}'", entry));
+        }
+        presses.push(KeyPress { key, first_frame, last_frame });
+    }
+    Ok(presses)
+}
+
+// A front end with no window or terminal, for running ROMs unattended. Key
+// input comes from a script of frame numbers, counted from 0. The keypad is
+// read at the end of each frame, so keys take effect from frame 1 on. When
+// the game waits for a key, the next unused press in the script answers it,
+// even if it is scripted for a later frame; the script then carries on from
+// that frame, but the frame limit still counts the frames actually run.
+// check_keys reports a quit once the frame limit is reached, or get_key once
+// the script runs out.
+pub struct HeadlessFrontend {
+    script: Vec<KeyPress>,
+    answered: Vec<bool>,
+    frame: u64,
+    // How far answering waits has moved the script ahead of the frames run.
+    script_offset: u64,
+    frame_limit: Option<u64>,
+    display: Display,
+}
+
+impl HeadlessFrontend {
+    pub fn new(script: Vec<KeyPress>, frame_limit: Option<u64>) -> Self {
+        HeadlessFrontend { answered: vec![false; script.len()],
+                           script,
+                           frame: 0,
+                           script_offset: 0,
+                           frame_limit,
+                           display: Display::new(DISPWIDTH, DISPHEIGHT),
+        }
+    }
+    // Frames completed so far.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+    // The frame the key script has reached, which waits can move ahead.
+    pub fn script_frame(&self) -> u64 {
+        self.frame + self.script_offset
+    }
+    // The last frame drawn.
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+}
+
+impl Default for HeadlessFrontend {
+    fn default() -> Self {
+        Self::new(Vec::new(), None)
+    }
+}
+
+impl Frontend for HeadlessFrontend {
+    fn draw_graphics(&mut self, display: &Display) {
+        self.display = display.clone();
+    }
+    fn start_tone(&mut self, _tone: Tone) {}
+    fn stop_tone(&mut self) {}
+    fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]> {
+        self.frame += 1;
+        if self.frame_limit.is_some_and(|limit| self.frame >= limit) {
+            return None;
+        }
+        let frame = self.script_frame();
+        let mut result = [false; NUM_KEYS];
+        for press in self.script.iter() {
+            if press.first_frame <= frame && frame <= press.last_frame {
+                result[press.key as usize] = true;
+            }
+        }
+        Some(result)
+    }
+    fn get_key(&mut self) -> Option<u8> {
+        let frame = self.script_frame();
+        let next = self.script.iter().zip(self.answered.iter())
+            .enumerate()
+            .filter(|&(_, (press, &answered))| !answered && press.last_frame >= frame)
+            .min_by_key(|&(_, (press, _))| press.first_frame)
+            .map(|(i, _)| i)?;
+        self.answered[next] = true;
+        self.script_offset += self.script[next].first_frame.saturating_sub(frame);
+        Some(self.script[next].key)
+    }
+    fn print_debug(&mut self, _text: &str) {}
+}
+
+// The game loop without the clock, debugger or hotkeys, shared by --headless
+// and the test harnesses. Each frame runs cycles_per_frame instructions,
+// answering FX0A from the front end, then ticks the timers, passes on sound
+// and drawing and reads the keypad. Stops when the front end quits, the
+// program exits, or the program counter reaches until_pc, and returns the
+// number of frames run.
+pub fn run_headless<F>(chip8: &mut Chip8, mut frontend: F, cycles_per_frame: usize,
+                       until_pc: Option<usize>) -> Result<u64, Chip8Error>
+    where F: Frontend {
+    let mut frames = 0;
+    'frames: loop {
+        let mut cycles = cycles_per_frame;
+        while cycles > 0 {
+            cycles -= chip8.run_until(cycles, |chip8| until_pc == Some(chip8.pc()))?;
+            if chip8.exited() || until_pc == Some(chip8.pc()) { break 'frames; }
+            if chip8.waiting_for_key().is_some() {
+                match frontend.get_key() {
+                    Some(key) => chip8.give_key(key),
+                    None => break 'frames,
+                }
+            }
+        }
+        frames += 1;
+        chip8.tick_timers();
+        match chip8.poll_sound_event() {
+            Some(SoundEvent::Start(tone)) => frontend.start_tone(tone),
+            Some(SoundEvent::Stop) => frontend.stop_tone(),
+            None => {},
+        }
+        if chip8.take_draw_flag() {
+            frontend.draw_graphics(chip8.display());
+        }
+        match frontend.check_keys() {
+            Some(keys) => chip8.set_keys(keys),
+            None => break,
+        }
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_key_scripts() {
+        assert_eq!(parse_key_script("10:5, 30-45:a").unwrap(),
+                   vec![KeyPress { key: 5, first_frame: 10, last_frame: 10 },
+                        KeyPress { key: 0xA, first_frame: 30, last_frame: 45 }]);
+        assert!(parse_key_script("10:G").is_err());
+        assert!(parse_key_script("9-3:1").is_err());
+        assert!(parse_key_script("12").is_err());
+    }
+    #[test]
+    fn scripted_keys_and_frame_limit() {
+        let mut frontend = HeadlessFrontend::new(parse_key_script("2-3:4,20:7").unwrap(), Some(5));
+        assert_eq!(frontend.check_keys().map(|keys| keys[4]), Some(false));
+        assert_eq!(frontend.check_keys().map(|keys| keys[4]), Some(true));
+        assert_eq!(frontend.get_key(), Some(4));
+        assert_eq!(frontend.get_key(), Some(7));
+        assert_eq!((frontend.frame(), frontend.script_frame()), (2, 20));
+        assert_eq!(frontend.get_key(), None);
+        // The wait moved the script on, not the count of frames run.
+        assert_eq!(frontend.check_keys().map(|keys| keys[7]), Some(false));
+        assert_eq!(frontend.check_keys().map(|keys| keys[4]), Some(false));
+        assert_eq!(frontend.frame(), 4);
+        assert_eq!(frontend.check_keys(), None);
+    }
+}
@@ -1,21 +1,96 @@
+//! A CHIP-8, SUPER-CHIP and XO-CHIP interpreter, with the assembler,
+//! disassembler, debugger and front ends the rschip8 binary is built from.
+//!
+//! To embed the interpreter, create a `Chip8`, load a ROM and run it a frame
+//! at a time, feeding it keys and reading the screen back:
+//!
+//! ```
+//! extern crate rschip8;
+//!
+//! use rschip8::{Chip8, Quirks};
+//!
+//! # fn main() -> Result<(), rschip8::Chip8Error> {
+//! let mut chip8 = Chip8::new(Quirks::default());
+//! // LD V0, 5; LD F, V0; DRW V0, V0, 5; JP 0x206
+//! chip8.load(vec![0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06])?;
+//! for _ in 0..60 {
+//!     chip8.set_keys([false; 16]);
+//!     chip8.run_frame(8)?;
+//!     if chip8.waiting_for_key().is_some() {
+//!         chip8.give_key(0);
+//!     }
+//!     if chip8.take_draw_flag() {
+//!         println!("{}", chip8.display().to_text());
+//!     }
+//!     if chip8.exited() {
+//!         break;
+//!     }
+//! }
+//! assert_eq!(chip8.cpu_state().pc, 0x206);
+//! assert!(chip8.display().get(5, 5));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `run_headless` drives the same loop against any `Frontend`.
+
 extern crate rand;
 extern crate termion;
 extern crate sdl2;
+extern crate png;
+extern crate gif;
 
 const DISPWIDTH: usize = 64;
 const DISPHEIGHT: usize = 32;
-const DISPSIZE: usize = DISPWIDTH * DISPHEIGHT;
-const MEMSIZE: usize = 4096;
+const HIRES_WIDTH: usize = 128;
+const HIRES_HEIGHT: usize = 64;
+// XO-CHIP's 64K address space; classic ROMs only use the first 4K.
+const MEMSIZE: usize = 65536;
 const STACKSIZE: usize = 16;
 const NUM_KEYS: usize = 16;
 const NUM_REGS: usize = 16;
 
+mod asm;
+mod conformance;
 mod cpu;
+pub mod debugger;
+mod disasm;
+mod display;
+mod error;
+mod gdb;
+mod movie;
+mod quirks;
+mod rewind;
+mod rng;
+mod screenshot;
+mod sound;
+mod state;
+mod video;
+mod wav;
 mod termion_frontend;
 mod sdl2_frontend;
+mod headless_frontend;
 mod frontend;
 
-pub use cpu::Chip8;
+pub use asm::{assemble, AsmError};
+pub use conformance::{find_glyphs, read_results, run_suite_rom, run_test_rom, Glyph, GlyphMatch,
+                      PASS_GLYPH, FAIL_GLYPH, ON_GLYPH, OFF_GLYPH, RESULT_GLYPHS,
+                      SUITE_PASS_GLYPH, SUITE_FAIL_GLYPH, SUITE_GLYPHS, SUITE_MENU_ADDRESS};
+pub use cpu::{Chip8, Chip8Builder, Chip8Op, CpuState, decode_opcode, MemoryAccess, AccessKind};
+pub use disasm::{disassemble, trace, Instruction, Listing};
+pub use debugger::{Debugger, Watchpoint};
+pub use display::Display;
+pub use error::Chip8Error;
+pub use gdb::GdbServer;
+pub use movie::{rom_hash, Movie, MovieFrame, MoviePlayer, MovieRecorder};
+pub use quirks::Quirks;
+pub use rewind::RewindBuffer;
+pub use rng::{random_seed, Rng};
+pub use screenshot::{ImageFormat, Palette, DEFAULT_PALETTE, DEFAULT_SCALE, parse_palette, save_image, write_image};
+pub use sound::{SoundEvent, Tone};
+pub use video::{VideoEncoder, VideoFormat, VideoRecorder};
+pub use wav::{WavEncoder, WavRecorder, WAV_SAMPLE_RATE};
 pub use termion_frontend::TermionFrontend;
-pub use sdl2_frontend::SDL2Frontend;
-pub use frontend::Frontend;
\ No newline at end of file
+pub use sdl2_frontend::{SDL2Frontend, DEFAULT_TONE_HZ, DEFAULT_VOLUME};
+pub use headless_frontend::{HeadlessFrontend, KeyPress, parse_key_script, run_headless};
+pub use frontend::{Frontend, Hotkey};
\ No newline at end of file
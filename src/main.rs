@@ -1,23 +1,30 @@
 extern crate getopts;
 extern crate rschip8;
 
-use std::io::Write;
+use std::io::{self, BufWriter, Write};
 use std::env;
-use std::{thread, time};
 use std::error::Error;
+use std::fs;
+use std::{thread, time};
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 use getopts::Options;
 use rschip8::*;
 
+const FRAME_RATE: f64 = 60.0;
+// Frames differ by a few hundred bytes at most, so this holds minutes of play.
+const REWIND_BYTES: usize = 8 << 20;
+
 fn print_usage(program: &str, opts: Options) {
-    print!("{}", opts.usage(&brief(&program)));
+    print!("{}", opts.usage(&brief(program)));
 }
 
 fn brief<ProgramName>(program: ProgramName) -> String
         where ProgramName: std::fmt::Display {
-    return format!("Usage: {} -g GAME [-c CLOCK_SPEED] [(-q|-v|--vv)]", program);
+    format!("Usage: {} -g GAME [-c CLOCK_SPEED] [-t] [--quirks PROFILE] [--vv] [--debug] [--gdb PORT]\n       \
+             [--load-state FILE] [--record FILE | --play FILE] [--record-video FILE] [--record-audio FILE]\n       \
+             [--headless (--frames N | --until-pc ADDR) [--keys SCRIPT] [--screen FILE]]", program)
 }
 
 fn main() {
@@ -28,13 +35,35 @@ fn main() {
     opts.reqopt("g", "", "path to game rom", "GAME");
     opts.optopt("c", "", "clock speed (Hz)", "CLOCK_SPEED");
     opts.optflag("t", "", "terminal display");
+    opts.optopt("", "tone", "buzzer frequency (Hz)", "HZ");
+    opts.optopt("", "volume", "buzzer volume (0-100)", "PERCENT");
+    opts.optopt("", "quirks", "interpreter quirks: vip (default), chip48, schip or xochip", "PROFILE");
     opts.optflag("", "vv", "print opcodes and disable display");
+    opts.optflag("", "debug", "start paused in the interactive debugger");
+    opts.optopt("", "gdb", "wait for a GDB remote protocol client on a local port", "PORT");
+    opts.optopt("", "load-state", "start from a saved state instead of the beginning", "FILE");
+    opts.optopt("", "record", "record the random seed and every key press to a movie file", "FILE");
+    opts.optopt("", "play", "replay a movie file recorded with --record", "FILE");
+    opts.optopt("", "shot-format", "screenshot format: png (default) or pbm", "FORMAT");
+    opts.optopt("", "shot-scale", "size of a display pixel in png screenshots (default 8)", "N");
+    opts.optopt("", "shot-palette", "png screenshot colours for off, plane 1, plane 2 and both planes \
+                                     as RRGGBB,RRGGBB,RRGGBB,RRGGBB", "COLOURS");
+    opts.optopt("", "record-video", "record what is drawn to an animated .gif, or to a .y4m video \
+                                     for an encoder; - streams y4m to standard output", "FILE");
+    opts.optopt("", "record-audio", "record the buzzer to a .wav file, using --tone and --volume", "FILE");
+    opts.optflag("", "headless", "run as fast as possible with no display or keyboard");
+    opts.optopt("", "frames", "with --headless, stop after this many frames", "N");
+    opts.optopt("", "until-pc", "with --headless, stop when the program counter reaches ADDR (hex)", "ADDR");
+    opts.optopt("", "keys", "with --headless, keys to press, e.g. 10:5,30-45:A for key 5 on frame 10 \
+                             and key A from frame 30 to 45", "SCRIPT");
+    opts.optopt("", "screen", "with --headless, write the final display to FILE, as an image if \
+                               it ends in .png or .pbm and as text otherwise", "FILE");
     opts.optflag("h", "help", "print this help message");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
         Err(f) => {let message = format!("{}\n{}\n",
-                                  f.to_string(),
+                                  f,
                                   opts.usage(&brief(&args[0])));
             if let Err(err) = write!(std::io::stderr(), "{}", message) {
                 panic!("Failed to write to standard error: {}\n\
@@ -51,6 +80,12 @@ fn main() {
         return;
     }
     
+    let video_to_stdout = matches.opt_str("record-video").as_deref() == Some("-");
+    if video_to_stdout && !matches.opt_present("headless") {
+        eprintln!("--record-video - needs --headless");
+        std::process::exit(1);
+    }
+
     let game_path = match matches.opt_str("g") {
         Some(s) => s,
         None => "./games/BRIX".to_string(),
@@ -60,19 +95,17 @@ fn main() {
     let display = path.display();
 
     // Open the path in read-only mode, returns `io::Result<File>`
-    let mut file = match File::open(&path) {
-        // The `description` method of `io::Error` returns a string that
-        // describes the error
-        Err(why) => panic!("couldn't open {}: {}", display,
-                                                   why.description()),
+    let mut file = match File::open(path) {
+        Err(why) => panic!("couldn't open {}: {}", display, why),
         Ok(file) => file,
     };
 
     // Read the file contents into a Vec<u8>, returns `io::Result<usize>`
     let mut buffer = Vec::new();
     match file.read_to_end(&mut buffer) {
-        Err(why) => panic!("couldn't read {}: {}", display,
-                                                   why.description()),
+        Err(why) => panic!("couldn't read {}: {}", display, why),
+        // Keep standard output clean when the video is streamed there.
+        Ok(_) if video_to_stdout => {eprintln!("loaded {}", display)},
         Ok(_) => {println!("loaded {}", display)},
     }
 
@@ -81,44 +114,381 @@ fn main() {
         None => 500.0,
     };
 
-    let mut chip8 = Chip8::new();
-    chip8.trace_flag = matches.opt_present("vv");
-    chip8.load(buffer);
+    let mut quirks = match matches.opt_str("quirks") {
+        Some(name) => match Quirks::from_name(&name) {
+            Some(quirks) => quirks,
+            None => {
+                eprintln!("unknown quirks profile '{}'", name);
+                std::process::exit(1);
+            }
+        },
+        None => Quirks::default(),
+    };
+
+    let mut cycles_per_frame = ((clock_speed / FRAME_RATE).round() as usize).max(1);
+
+    if matches.opt_present("record") || matches.opt_present("play") {
+        let conflicts = ["record", "play", "load-state", "debug", "gdb"];
+        let present: Vec<&str> = conflicts.iter().cloned().filter(|&name| matches.opt_present(name)).collect();
+        if present.len() > 1 {
+            eprintln!("--{} can't be used with --{}", present[0], present[1]);
+            std::process::exit(1);
+        }
+    }
+
+    // A movie brings its own quirks, clock speed and random seed.
+    let movie = match matches.opt_str("play") {
+        Some(movie_path) => {
+            let movie = match load_movie_file(&movie_path) {
+                Ok(movie) => movie,
+                Err(why) => {
+                    eprintln!("couldn't load movie {}: {}", movie_path, why);
+                    std::process::exit(1);
+                }
+            };
+            if movie.rom_hash != rom_hash(&buffer) {
+                eprintln!("{} was recorded with a different rom", movie_path);
+                std::process::exit(1);
+            }
+            quirks = movie.quirks;
+            cycles_per_frame = movie.cycles_per_frame as usize;
+            Some(movie)
+        },
+        None => None,
+    };
+    let seed = match movie {
+        Some(ref movie) => movie.seed,
+        None => random_seed(),
+    };
+    let recording = matches.opt_str("record").map(|movie_path| {
+        (movie_path, Movie::new(seed, &buffer, quirks, cycles_per_frame as u32))
+    });
+
+    let mut chip8 = Chip8::new(quirks);
+    chip8.seed_rng(seed);
+    chip8.set_trace(matches.opt_present("vv"));
+    if let Err(why) = chip8.load(buffer) {
+        panic!("couldn't load {}: {}", display, why);
+    }
+    if let Some(state_path) = matches.opt_str("load-state") {
+        if let Err(why) = load_state_file(&mut chip8, &state_path) {
+            eprintln!("couldn't load state {}: {}", state_path, why);
+            std::process::exit(1);
+        }
+    }
+
+    let tone_hz = match matches.opt_str("tone") {
+        Some(s) => s.parse::<f32>().unwrap(),
+        None => DEFAULT_TONE_HZ,
+    };
+    let volume = match matches.opt_str("volume") {
+        Some(s) => s.parse::<f32>().unwrap() / 100.0,
+        None => DEFAULT_VOLUME,
+    };
+
+    let gdb = match matches.opt_str("gdb") {
+        Some(port) => match GdbServer::bind(("127.0.0.1", port.parse::<u16>().unwrap())) {
+            Ok(server) => {
+                println!("waiting for gdb on port {}", port);
+                Some(server)
+            },
+            Err(why) => {
+                eprintln!("couldn't listen on port {}: {}", port, why);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let debugger = if matches.opt_present("debug") || gdb.is_some() {
+        let mut debugger = Debugger::new();
+        debugger.pause(&chip8);
+        Some(debugger)
+    } else { None };
 
-    let clock_period_ns = (1.0 / clock_speed * 1_000_000_000.0).floor() as u32;
-    let sleep_duration = time::Duration::new(0, clock_period_ns);
+    let shot_scale = match matches.opt_str("shot-scale") {
+        Some(s) => s.parse::<u32>().unwrap(),
+        None => DEFAULT_SCALE,
+    };
+    let shot_palette = match matches.opt_str("shot-palette").map(|text| parse_palette(&text)) {
+        Some(Ok(palette)) => palette,
+        Some(Err(why)) => {
+            eprintln!("bad palette: {}", why);
+            std::process::exit(1);
+        },
+        None => DEFAULT_PALETTE,
+    };
+    let screenshot = match matches.opt_str("shot-format").as_deref() {
+        None | Some("png") => ImageFormat::Png { scale: shot_scale, palette: shot_palette },
+        Some("pbm") => ImageFormat::Pbm,
+        Some(other) => {
+            eprintln!("unknown screenshot format '{}'", other);
+            std::process::exit(1);
+        }
+    };
 
-    if matches.opt_present("t") {
-        run_game_loop(chip8, TermionFrontend::new(), sleep_duration);
+    let headless = matches.opt_present("headless");
+    if headless {
+        if matches.opt_present("debug") || matches.opt_present("gdb") {
+            eprintln!("--headless can't be used with the debugger");
+            std::process::exit(1);
+        }
+        if !matches.opt_present("frames") && !matches.opt_present("until-pc") {
+            eprintln!("--headless needs --frames or --until-pc");
+            std::process::exit(1);
+        }
+    }
+    let until_pc = matches.opt_str("until-pc").map(|addr| match usize::from_str_radix(&addr, 16) {
+        Ok(addr) => addr,
+        Err(_) => {
+            eprintln!("bad address '{}'", addr);
+            std::process::exit(1);
+        }
+    });
+
+    let mut frontend: Box<dyn Frontend> = if headless {
+        let script = match parse_key_script(&matches.opt_str("keys").unwrap_or_default()) {
+            Ok(script) => script,
+            Err(why) => {
+                eprintln!("bad key script: {}", why);
+                std::process::exit(1);
+            }
+        };
+        let frames = matches.opt_str("frames").map(|frames| frames.parse::<u64>().unwrap());
+        Box::new(HeadlessFrontend::new(script, frames))
+    } else if matches.opt_present("t") {
+        Box::new(TermionFrontend::new())
     } else {
-        run_game_loop(chip8, SDL2Frontend::new(), sleep_duration);
+        Box::new(SDL2Frontend::with_tone(tone_hz, volume))
+    };
+    if let Some(movie) = movie {
+        frontend = Box::new(MoviePlayer::new(movie, frontend));
+    }
+    if let Some((movie_path, movie)) = recording {
+        frontend = match MovieRecorder::create(&movie_path, &movie, frontend) {
+            Ok(recorder) => Box::new(recorder),
+            Err(why) => {
+                eprintln!("couldn't create movie {}: {}", movie_path, why);
+                std::process::exit(1);
+            }
+        };
     }
+
+    // Videos use the screenshot colours, with low-res pixels at the screenshot scale.
+    if let Some(video_path) = matches.opt_str("record-video") {
+        let (format, out): (VideoFormat, io::Result<Box<dyn Write>>) = if video_to_stdout {
+            (VideoFormat::Y4m, Ok(Box::new(io::stdout())))
+        } else {
+            match VideoFormat::from_path(&video_path) {
+                Some(format) => (format, File::create(&video_path).map(|file| Box::new(BufWriter::new(file)) as Box<dyn Write>)),
+                None => {
+                    eprintln!("--record-video needs a .gif or .y4m file");
+                    std::process::exit(1);
+                }
+            }
+        };
+        match out.and_then(|out| VideoEncoder::new(out, format, (shot_scale / 2).max(1), shot_palette)) {
+            Ok(encoder) => frontend = Box::new(VideoRecorder::new(encoder, frontend)),
+            Err(why) => {
+                eprintln!("couldn't record video to {}: {}", video_path, why);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(audio_path) = matches.opt_str("record-audio") {
+        match File::create(&audio_path).and_then(|file| WavEncoder::new(BufWriter::new(file), tone_hz, volume)) {
+            Ok(encoder) => frontend = Box::new(WavRecorder::new(encoder, frontend)),
+            Err(why) => {
+                eprintln!("couldn't record audio to {}: {}", audio_path, why);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if headless {
+        let frames = match run_headless(&mut chip8, frontend, cycles_per_frame, until_pc) {
+            Ok(frames) => frames,
+            Err(why) => {
+                eprintln!("emulation halted: {}", why);
+                std::process::exit(1);
+            }
+        };
+        let summary = format!("stopped after {} frames at {:03X}", frames, chip8.pc());
+        if video_to_stdout { eprintln!("{}", summary); } else { println!("{}", summary); }
+        if let Some(screen_path) = matches.opt_str("screen") {
+            let written = match ImageFormat::from_path(&screen_path) {
+                Some(ImageFormat::Pbm) => chip8.save_screenshot(&screen_path, ImageFormat::Pbm),
+                Some(ImageFormat::Png { .. }) => {
+                    chip8.save_screenshot(&screen_path, ImageFormat::Png { scale: shot_scale, palette: shot_palette })
+                },
+                None => fs::write(&screen_path, chip8.display().to_text()),
+            };
+            if let Err(why) = written {
+                eprintln!("couldn't write {}: {}", screen_path, why);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let result = run_game_loop(chip8, frontend, &game_path, screenshot, cycles_per_frame, debugger, gdb);
+    if let Err(why) = result {
+        eprintln!("emulation halted: {}", why);
+        std::process::exit(1);
+    }
+}
+
+fn load_state_file(chip8: &mut Chip8, path: &str) -> Result<(), Box<dyn Error>> {
+    let state = fs::read(path)?;
+    chip8.load_state(&state)?;
+    Ok(())
+}
+
+fn load_movie_file(path: &str) -> Result<Movie, Box<dyn Error>> {
+    let movie = Movie::from_bytes(&fs::read(path)?)?;
+    Ok(movie)
+}
+
+// Quick-save slots live next to the ROM, e.g. games/PONG.state1.
+fn state_slot_path(game_path: &str, slot: u8) -> String {
+    format!("{}.state{}", game_path, slot)
 }
 
-fn run_game_loop<T>(mut chip8: Chip8, mut frontend: T, sleep_duration: std::time::Duration)
+// Screenshots are numbered from 1 next to the ROM, skipping numbers in use.
+fn screenshot_path(game_path: &str, format: ImageFormat) -> String {
+    (1..).map(|n| format!("{}-{}.{}", game_path, n, format.extension()))
+        .find(|path| !Path::new(path).exists())
+        .unwrap()
+}
+
+// Saves or loads a quick-save slot or takes a screenshot, and says how it went.
+fn handle_hotkey(chip8: &mut Chip8, game_path: &str, screenshot: ImageFormat, hotkey: Hotkey) -> String {
+    match hotkey {
+        Hotkey::Rewind => String::new(),
+        Hotkey::Screenshot => {
+            let path = screenshot_path(game_path, screenshot);
+            match chip8.save_screenshot(&path, screenshot) {
+                Ok(()) => format!("saved screenshot to {}", path),
+                Err(why) => format!("couldn't save screenshot {}: {}", path, why),
+            }
+        },
+        Hotkey::SaveState(slot) => {
+            let path = state_slot_path(game_path, slot);
+            match fs::write(&path, chip8.save_state()) {
+                Ok(()) => format!("saved state to {}", path),
+                Err(why) => format!("couldn't save state {}: {}", path, why),
+            }
+        },
+        Hotkey::LoadState(slot) => {
+            let path = state_slot_path(game_path, slot);
+            match load_state_file(chip8, &path) {
+                Ok(()) => format!("loaded state from {}", path),
+                Err(why) => format!("couldn't load state {}: {}", path, why),
+            }
+        },
+    }
+}
+
+// Runs cycles_per_frame instructions, then ticks the timers and refreshes the
+// display once per 60 Hz frame, so game speed does not depend on clock speed.
+fn run_game_loop<T>(mut chip8: Chip8, mut frontend: T, game_path: &str, screenshot: ImageFormat,
+                    cycles_per_frame: usize, mut debugger: Option<Debugger>, mut gdb: Option<GdbServer>)
+        -> Result<(), Chip8Error>
     where T: Frontend {
-    loop {
-        chip8.emulate_cycle();
+    let frame_duration = time::Duration::new(0, (1_000_000_000.0 / FRAME_RATE) as u32);
+    let mut next_frame = time::Instant::now() + frame_duration;
+    let mut rewind = RewindBuffer::new(REWIND_BYTES);
+    // Set while the rewind hotkey is held; the game stands still meanwhile.
+    let mut rewinding = false;
+    'frames: loop {
+        if let (Some(ref mut gdb), Some(ref mut debugger)) = (gdb.as_mut(), debugger.as_mut()) {
+            gdb.poll(&mut chip8, debugger);
+        }
+        if let Some(ref mut debugger) = debugger {
+            while let Some(line) = frontend.poll_debug_command() {
+                match debugger::parse_command(&line) {
+                    Ok(command) => debugger.execute(command, &mut chip8),
+                    Err(why) => frontend.print_debug(&format!("{}\n", why)),
+                }
+            }
+        }
 
-        if chip8.wait_for_key_flag {
-            if let Some(key) = frontend.get_key() {
-                chip8.give_key(key);
-                chip8.wait_for_key_flag = false;
-            } else { break; }
+        let paused = debugger.as_ref().is_some_and(|debugger| debugger.is_paused());
+        if !rewinding && !paused {
+            rewind.push(chip8.save_state());
         }
 
-        if chip8.beep_flag {
-            frontend.beep();
+        let cycles = if rewinding { 0 } else { cycles_per_frame };
+        for _ in 0..cycles {
+            if let Some(ref mut debugger) = debugger {
+                if !debugger.before_cycle(&chip8) { break; }
+                // Under the debugger a bad instruction pauses instead of quitting.
+                if let Err(why) = chip8.emulate_cycle() {
+                    frontend.print_debug(&format!("{}\n", why));
+                    debugger.pause(&chip8);
+                    break;
+                }
+                debugger.after_cycle(&chip8);
+            } else {
+                chip8.emulate_cycle()?;
+            }
+            if chip8.exited() { break 'frames; }
+
+            if chip8.waiting_for_key().is_some() {
+                if let Some(key) = frontend.get_key() {
+                    chip8.give_key(key);
+                    next_frame = time::Instant::now();
+                } else { break 'frames; }
+            }
+        }
+        // Timers stand still while the debugger has execution paused.
+        if !rewinding && !debugger.as_ref().is_some_and(|debugger| debugger.is_paused()) {
+            chip8.tick_timers();
+        }
+        if let Some(text) = debugger.as_mut().and_then(|debugger| debugger.take_output()) {
+            frontend.print_debug(&text);
         }
 
-        if chip8.draw_flag && !chip8.trace_flag {
-            frontend.draw_graphics(chip8.display);
+        match chip8.poll_sound_event() {
+            Some(SoundEvent::Start(tone)) => frontend.start_tone(tone),
+            Some(SoundEvent::Stop) => frontend.stop_tone(),
+            None => {},
         }
 
-        thread::sleep(sleep_duration);
+        if chip8.take_draw_flag() && !chip8.tracing() {
+            frontend.draw_graphics(chip8.display());
+        }
+
+        let now = time::Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+            next_frame += frame_duration;
+        } else {
+            // Running behind; don't try to catch up with a burst of frames.
+            next_frame = now + frame_duration;
+        }
 
         if let Some(keys_pressed) = frontend.check_keys() {
             chip8.set_keys(keys_pressed);
         } else { break; }
+        rewinding = false;
+        while let Some(hotkey) = frontend.poll_hotkey() {
+            if let Some(ref mut debugger) = debugger {
+                if let Hotkey::Rewind | Hotkey::LoadState(_) = hotkey {
+                    debugger.clear_history();
+                }
+            }
+            if hotkey == Hotkey::Rewind {
+                if let Some(state) = rewind.pop() {
+                    chip8.load_state(&state)?;
+                    rewinding = true;
+                }
+                continue;
+            }
+            let message = handle_hotkey(&mut chip8, game_path, screenshot, hotkey);
+            frontend.print_debug(&format!("{}\n", message));
+        }
     }
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,308 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use display::Display;
+use error::Chip8Error;
+use frontend::{Frontend, Hotkey};
+use quirks::Quirks;
+use sound::Tone;
+use state::{StateReader, StateWriter};
+use NUM_KEYS;
+
+const MOVIE_MAGIC: &[u8; 4] = b"C8MV";
+const MOVIE_VERSION: u8 = 1;
+
+// FNV-1a, to check that a movie is played back on the ROM it was made with.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+// The input for one 60 Hz frame: the keys given to FX0A while the frame ran,
+// in order, then the keypad state read at the end of the frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MovieFrame {
+    pub waited_keys: Vec<u8>,
+    pub keys: [bool; NUM_KEYS],
+}
+
+// Everything needed to replay a session exactly: the machine starts from
+// the ROM with the given quirks and random seed, runs cycles_per_frame
+// instructions a frame and sees the recorded input. The header is followed
+// by one record per frame until the end of the file, so a recording cut
+// short still plays back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub seed: u64,
+    pub rom_hash: u64,
+    pub quirks: Quirks,
+    pub cycles_per_frame: u32,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new(seed: u64, rom: &[u8], quirks: Quirks, cycles_per_frame: u32) -> Self {
+        Movie { seed,
+                rom_hash: rom_hash(rom),
+                quirks,
+                cycles_per_frame,
+                frames: Vec::new(),
+        }
+    }
+    fn header_bytes(&self) -> Vec<u8> {
+        let mut movie = StateWriter::new();
+        movie.header(MOVIE_MAGIC, MOVIE_VERSION);
+        movie.u64(self.seed);
+        movie.u64(self.rom_hash);
+        movie.quirks(&self.quirks);
+        movie.u32(self.cycles_per_frame);
+        movie.finish()
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header_bytes();
+        for frame in self.frames.iter() {
+            bytes.extend(frame_bytes(frame));
+        }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Chip8Error> {
+        let mut movie = StateReader::new(bytes, |reason| Chip8Error::BadMovie { reason });
+        movie.header(MOVIE_MAGIC, MOVIE_VERSION)?;
+        let seed = movie.u64()?;
+        let rom_hash = movie.u64()?;
+        let quirks = movie.quirks()?;
+        let cycles_per_frame = movie.u32()?;
+        let mut frames = Vec::new();
+        while !movie.is_empty() {
+            let mask = movie.u16()?;
+            let mut keys = [false; NUM_KEYS];
+            for (key, pressed) in keys.iter_mut().enumerate() {
+                *pressed = mask & (1 << key) != 0;
+            }
+            let waits = movie.u32()? as usize;
+            let waited_keys = movie.bytes(waits)?.to_vec();
+            if waited_keys.iter().any(|&key| key as usize >= NUM_KEYS) {
+                return Err(movie.error("key out of range"));
+            }
+            frames.push(MovieFrame { waited_keys, keys });
+        }
+        Ok(Movie { seed, rom_hash, quirks, cycles_per_frame, frames })
+    }
+}
+
+fn frame_bytes(frame: &MovieFrame) -> Vec<u8> {
+    let mut bytes = StateWriter::new();
+    let mask = frame.keys.iter().enumerate()
+        .filter(|&(_, &pressed)| pressed)
+        .fold(0u16, |mask, (key, _)| mask | 1 << key);
+    bytes.u16(mask);
+    // A frame cannot wait more often than it has cycles, and those are
+    // counted in a u32 too.
+    bytes.u32(frame.waited_keys.len() as u32);
+    bytes.bytes(&frame.waited_keys);
+    bytes.finish()
+}
+
+// Hotkeys that would take the machine somewhere the movie cannot follow are
+// dropped while recording or playing.
+fn keeps_movie_in_sync(hotkey: &Hotkey) -> bool {
+    match *hotkey {
+        Hotkey::SaveState(_) | Hotkey::Screenshot => true,
+        Hotkey::LoadState(_) | Hotkey::Rewind => false,
+    }
+}
+
+// Wraps a front end and writes everything typed on it to a movie file, one
+// frame at a time.
+pub struct MovieRecorder<F: Frontend> {
+    inner: F,
+    file: BufWriter<File>,
+    waited_keys: Vec<u8>,
+}
+
+impl<F: Frontend> MovieRecorder<F> {
+    pub fn create<P: AsRef<Path>>(path: P, movie: &Movie, inner: F) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&movie.to_bytes())?;
+        Ok(MovieRecorder { inner, file, waited_keys: Vec::new() })
+    }
+}
+
+impl<F: Frontend> Frontend for MovieRecorder<F> {
+    fn draw_graphics(&mut self, display: &Display) {
+        self.inner.draw_graphics(display);
+    }
+    fn start_tone(&mut self, tone: Tone) {
+        self.inner.start_tone(tone);
+    }
+    fn stop_tone(&mut self) {
+        self.inner.stop_tone();
+    }
+    fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]> {
+        let keys = self.inner.check_keys()?;
+        let frame = MovieFrame { waited_keys: self.waited_keys.split_off(0), keys };
+        // A movie that cannot be written is not worth stopping the game for.
+        if let Err(why) = self.file.write_all(&frame_bytes(&frame)) {
+            self.inner.print_debug(&format!("couldn't write movie: {}\n", why));
+        }
+        Some(keys)
+    }
+    fn get_key(&mut self) -> Option<u8> {
+        let key = self.inner.get_key()?;
+        self.waited_keys.push(key);
+        Some(key)
+    }
+    fn poll_debug_command(&mut self) -> Option<String> {
+        self.inner.poll_debug_command()
+    }
+    fn poll_hotkey(&mut self) -> Option<Hotkey> {
+        while let Some(hotkey) = self.inner.poll_hotkey() {
+            if keeps_movie_in_sync(&hotkey) { return Some(hotkey); }
+        }
+        None
+    }
+    fn print_debug(&mut self, text: &str) {
+        self.inner.print_debug(text);
+    }
+}
+
+// Wraps a front end and feeds the game the input from a movie instead of the
+// keyboard. The keyboard is still read, so the game can be quit, and takes
+// over once the movie runs out.
+pub struct MoviePlayer<F: Frontend> {
+    inner: F,
+    frames: ::std::vec::IntoIter<MovieFrame>,
+    current: Option<MovieFrame>,
+}
+
+impl<F: Frontend> MoviePlayer<F> {
+    pub fn new(movie: Movie, inner: F) -> Self {
+        let mut frames = movie.frames.into_iter();
+        let current = frames.next();
+        MoviePlayer { inner, frames, current }
+    }
+    pub fn is_finished(&self) -> bool {
+        self.current.is_none()
+    }
+}
+
+impl<F: Frontend> Frontend for MoviePlayer<F> {
+    fn draw_graphics(&mut self, display: &Display) {
+        self.inner.draw_graphics(display);
+    }
+    fn start_tone(&mut self, tone: Tone) {
+        self.inner.start_tone(tone);
+    }
+    fn stop_tone(&mut self) {
+        self.inner.stop_tone();
+    }
+    fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]> {
+        let live = self.inner.check_keys()?;
+        match self.current.take() {
+            Some(frame) => {
+                self.current = self.frames.next();
+                if self.current.is_none() {
+                    self.inner.print_debug("movie finished\n");
+                }
+                Some(frame.keys)
+            },
+            None => Some(live),
+        }
+    }
+    fn get_key(&mut self) -> Option<u8> {
+        if let Some(ref mut frame) = self.current {
+            if !frame.waited_keys.is_empty() {
+                return Some(frame.waited_keys.remove(0));
+            }
+        }
+        self.inner.get_key()
+    }
+    fn poll_debug_command(&mut self) -> Option<String> {
+        self.inner.poll_debug_command()
+    }
+    fn poll_hotkey(&mut self) -> Option<Hotkey> {
+        while let Some(hotkey) = self.inner.poll_hotkey() {
+            if self.is_finished() || keeps_movie_in_sync(&hotkey) { return Some(hotkey); }
+        }
+        None
+    }
+    fn print_debug(&mut self, text: &str) {
+        self.inner.print_debug(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Plays back a fixed list of key states and records what it was asked.
+    struct ScriptedFrontend {
+        frames: Vec<[bool; NUM_KEYS]>,
+        waited_keys: Vec<u8>,
+    }
+
+    impl Frontend for ScriptedFrontend {
+        fn draw_graphics(&mut self, _display: &Display) {}
+        fn start_tone(&mut self, _tone: Tone) {}
+        fn stop_tone(&mut self) {}
+        fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]> {
+            if self.frames.is_empty() { None } else { Some(self.frames.remove(0)) }
+        }
+        fn get_key(&mut self) -> Option<u8> {
+            if self.waited_keys.is_empty() { None } else { Some(self.waited_keys.remove(0)) }
+        }
+        fn print_debug(&mut self, _text: &str) {}
+    }
+
+    fn keys(pressed: &[usize]) -> [bool; NUM_KEYS] {
+        let mut keys = [false; NUM_KEYS];
+        for &key in pressed {
+            keys[key] = true;
+        }
+        keys
+    }
+
+    #[test]
+    fn movie_round_trips_through_bytes() {
+        let mut movie = Movie::new(99, &[0x12, 0x00], Quirks::superchip(), 11);
+        movie.frames.push(MovieFrame { waited_keys: vec![], keys: keys(&[2, 0xF]) });
+        movie.frames.push(MovieFrame { waited_keys: vec![5, 6], keys: keys(&[]) });
+        let bytes = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&bytes), Ok(movie));
+        assert_eq!(Movie::from_bytes(&bytes[..bytes.len() - 1]),
+                   Err(Chip8Error::BadMovie { reason: "file is truncated" }));
+        // More FX0A waits in a frame than a byte can count.
+        let mut busy = Movie::new(1, &[], Quirks::default(), 1000);
+        busy.frames.push(MovieFrame { waited_keys: vec![3; 300], keys: keys(&[]) });
+        assert_eq!(Movie::from_bytes(&busy.to_bytes()), Ok(busy));
+        assert_eq!(Movie::from_bytes(b"C8ST\x01"),
+                   Err(Chip8Error::BadMovie { reason: "unrecognised file type" }));
+    }
+    #[test]
+    fn recorded_input_plays_back() {
+        let path = ::std::env::temp_dir().join(format!("rschip8-movie-{}", ::std::process::id()));
+        let live = ScriptedFrontend { frames: vec![keys(&[1]), keys(&[]), keys(&[4, 8])], waited_keys: vec![7] };
+        let movie = Movie::new(1, &[], Quirks::default(), 8);
+        {
+            let mut recorder = MovieRecorder::create(&path, &movie, live).unwrap();
+            recorder.check_keys();
+            recorder.get_key();
+            recorder.check_keys();
+            recorder.check_keys();
+        }
+        let recorded = Movie::from_bytes(&::std::fs::read(&path).unwrap()).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+        assert_eq!(recorded.frames.len(), 3);
+
+        let idle = ScriptedFrontend { frames: vec![keys(&[]); 4], waited_keys: vec![] };
+        let mut player = MoviePlayer::new(recorded, idle);
+        assert_eq!(player.check_keys(), Some(keys(&[1])));
+        assert_eq!(player.get_key(), Some(7));
+        assert_eq!(player.check_keys(), Some(keys(&[])));
+        assert_eq!(player.check_keys(), Some(keys(&[4, 8])));
+        assert!(player.is_finished());
+        assert_eq!(player.get_key(), None);
+    }
+}
@@ -0,0 +1,73 @@
+// Behaviours that differ between CHIP-8 interpreters. Each preset matches
+// the platform ROMs were commonly written for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VX in place instead of copying VY >> 1 into VX.
+    pub shift_uses_vx: bool,
+    // FX55/FX65 leave I pointing past the last register stored or loaded.
+    pub load_store_increments_i: bool,
+    // BNNN jumps to XNN + VX instead of NNN + V0.
+    pub jump_uses_vx: bool,
+    // 8XY1/8XY2/8XY3 clear VF.
+    pub vf_reset: bool,
+    // Sprites are cut off at the screen edge instead of wrapping around.
+    pub clip_sprites: bool,
+    // DXYN waits for the next frame, so at most one sprite is drawn per frame.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    pub fn cosmac_vip() -> Self {
+        Quirks { shift_uses_vx: false,
+                 load_store_increments_i: true,
+                 jump_uses_vx: false,
+                 vf_reset: true,
+                 clip_sprites: true,
+                 display_wait: true,
+        }
+    }
+    // CHIP-48 actually advanced I by one less than the VIP did; no known ROM
+    // depends on the difference.
+    pub fn chip48() -> Self {
+        Quirks { shift_uses_vx: true,
+                 load_store_increments_i: true,
+                 jump_uses_vx: true,
+                 vf_reset: false,
+                 clip_sprites: true,
+                 display_wait: false,
+        }
+    }
+    pub fn superchip() -> Self {
+        Quirks { shift_uses_vx: true,
+                 load_store_increments_i: false,
+                 jump_uses_vx: true,
+                 vf_reset: false,
+                 clip_sprites: true,
+                 display_wait: false,
+        }
+    }
+    pub fn xochip() -> Self {
+        Quirks { shift_uses_vx: false,
+                 load_store_increments_i: true,
+                 jump_uses_vx: false,
+                 vf_reset: false,
+                 clip_sprites: false,
+                 display_wait: false,
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::superchip()),
+            "xochip" => Some(Quirks::xochip()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::cosmac_vip()
+    }
+}
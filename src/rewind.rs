@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+
+// History of save states, newest last. Only the newest state is kept whole;
+// each older one is stored as the XOR of it against its successor, with runs
+// of unchanged bytes squeezed out. Successive frames differ in a few dozen
+// bytes, so thousands of snapshots fit in a few megabytes. The oldest
+// snapshots are dropped once the deltas outgrow the capacity in bytes.
+pub struct RewindBuffer {
+    newest: Option<Vec<u8>>,
+    // deltas[i] turns snapshot i + 1 back into snapshot i.
+    deltas: VecDeque<Vec<u8>>,
+    size: usize,
+    capacity: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> Self {
+        RewindBuffer { newest: None,
+                       deltas: VecDeque::new(),
+                       size: 0,
+                       capacity,
+        }
+    }
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            let delta = encode_delta(&state, &newest);
+            self.size += delta.len();
+            self.deltas.push_back(delta);
+            while self.size > self.capacity {
+                match self.deltas.pop_front() {
+                    Some(oldest) => self.size -= oldest.len(),
+                    None => break,
+                }
+            }
+        }
+        self.newest = Some(state);
+    }
+    // Removes and returns the newest snapshot.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.newest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            self.size -= delta.len();
+            self.newest = Some(apply_delta(&state, &delta));
+        }
+        Some(state)
+    }
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.size = 0;
+    }
+}
+
+fn push_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 { return value; }
+        shift += 7;
+    }
+}
+
+// The delta is the length of old, then pairs of (unchanged run, changed run)
+// lengths, each changed run followed by its bytes XORed with new. Bytes
+// past the end of new count as zero, since the display grows and shrinks
+// with the resolution.
+fn encode_delta(new: &[u8], old: &[u8]) -> Vec<u8> {
+    let xor: Vec<u8> = old.iter().enumerate()
+        .map(|(i, &byte)| byte ^ new.get(i).cloned().unwrap_or(0))
+        .collect();
+    let mut delta = Vec::new();
+    push_varint(&mut delta, old.len());
+    let mut i = 0;
+    while i < xor.len() {
+        let start = i;
+        while i < xor.len() && xor[i] == 0 { i += 1; }
+        if i == xor.len() { break; }
+        let zeros = i - start;
+        let start = i;
+        while i < xor.len() && xor[i] != 0 { i += 1; }
+        push_varint(&mut delta, zeros);
+        push_varint(&mut delta, i - start);
+        delta.extend_from_slice(&xor[start..i]);
+    }
+    delta
+}
+
+fn apply_delta(new: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut old: Vec<u8> = (0..len).map(|i| new.get(i).cloned().unwrap_or(0)).collect();
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for byte in old[i..i + changed].iter_mut() {
+            *byte ^= delta[pos];
+            pos += 1;
+        }
+        i += changed;
+    }
+    old
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_snapshots_newest_first() {
+        let snapshots = [vec![0; 300], vec![0, 1, 2, 3], vec![9; 1000], vec![9, 0, 9]];
+        let mut buffer = RewindBuffer::new(1 << 20);
+        for snapshot in snapshots.iter() {
+            buffer.push(snapshot.clone());
+        }
+        assert_eq!(buffer.len(), 4);
+        for snapshot in snapshots.iter().rev() {
+            assert_eq!(buffer.pop().as_ref(), Some(snapshot));
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.pop(), None);
+    }
+    #[test]
+    fn small_changes_make_small_deltas() {
+        let mut old = vec![0x55; 65536];
+        let new = old.clone();
+        old[1000] = 0;
+        old[40000] = 1;
+        assert!(encode_delta(&new, &old).len() < 16);
+        assert_eq!(apply_delta(&new, &encode_delta(&new, &old)), old);
+    }
+    #[test]
+    fn drops_oldest_when_full() {
+        let mut buffer = RewindBuffer::new(10);
+        for i in 0..20 {
+            buffer.push(vec![i; 4]);
+        }
+        assert!(buffer.len() < 20);
+        assert_eq!(buffer.pop(), Some(vec![19; 4]));
+        assert_eq!(buffer.pop(), Some(vec![18; 4]));
+    }
+}
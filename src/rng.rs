@@ -0,0 +1,63 @@
+use rand;
+
+// xorshift64* generator for CXNN. Owning the generator, rather than asking
+// the thread RNG, lets a seed reproduce a whole session and lets save states
+// carry the generator along with the rest of the machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rng {
+    state: u64,
+}
+
+// A fresh seed for sessions that do not ask for a particular one.
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Run the seed through splitmix64 so that small seeds still start
+        // from a well mixed state. xorshift never leaves the all-zero state.
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        Rng { state: if z == 0 { 1 } else { z } }
+    }
+    pub fn next_u8(&mut self) -> u8 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+    pub(crate) fn state(&self) -> u64 {
+        self.state
+    }
+    pub(crate) fn from_state(state: u64) -> Option<Self> {
+        if state == 0 { None } else { Some(Rng { state }) }
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(random_seed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let first: Vec<u8> = (0..64).map(|_| a.next_u8()).collect();
+        let second: Vec<u8> = (0..64).map(|_| b.next_u8()).collect();
+        assert_eq!(first, second);
+        let mut c = Rng::new(43);
+        let third: Vec<u8> = (0..64).map(|_| c.next_u8()).collect();
+        assert_ne!(first, third);
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use png;
+use display::Display;
+
+// RGB colours for each combination of the two XO-CHIP bitplanes.
+pub type Palette = [(u8, u8, u8); 4];
+
+pub const DEFAULT_PALETTE: Palette = [(0, 0, 0), (0, 230, 20), (230, 120, 0), (250, 250, 200)];
+pub const DEFAULT_SCALE: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    // Each display pixel becomes a scale x scale block in the palette colour.
+    Png { scale: u32, palette: Palette },
+    // Plain (ASCII) bitmap at one image pixel per display pixel. Pixels lit
+    // on any plane are black.
+    Pbm,
+}
+
+impl ImageFormat {
+    // Picks the format from a file name ending in .png or .pbm.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(ImageFormat::default()),
+            "pbm" => Some(ImageFormat::Pbm),
+            _ => None,
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ImageFormat::Png { .. } => "png",
+            ImageFormat::Pbm => "pbm",
+        }
+    }
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        ImageFormat::Png { scale: DEFAULT_SCALE, palette: DEFAULT_PALETTE }
+    }
+}
+
+// Parses four comma separated RRGGBB colours, for planes off, first plane,
+// second plane and both.
+pub fn parse_palette(text: &str) -> Result<Palette, String> {
+    let colours: Vec<&str> = text.split(',').map(str::trim).collect();
+    if colours.len() != 4 {
+        return Err(format!("expected 4 colours, got {}", colours.len()));
+    }
+    let mut palette = DEFAULT_PALETTE;
+    for (entry, colour) in palette.iter_mut().zip(colours) {
+        let hex = colour.trim_start_matches('#');
+        let rgb = match u32::from_str_radix(hex, 16) {
+            Ok(rgb) if hex.len() == 6 => rgb,
+            _ => return Err(format!("bad colour '{}'", colour)),
+        };
+        *entry = ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+    }
+    Ok(palette)
+}
+
+pub fn write_image<W: Write>(display: &Display, format: ImageFormat, out: W) -> io::Result<()> {
+    match format {
+        ImageFormat::Png { scale, palette } => write_png(display, scale, &palette, out),
+        ImageFormat::Pbm => write_pbm(display, out),
+    }
+}
+
+pub fn save_image<P: AsRef<Path>>(display: &Display, format: ImageFormat, path: P) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_image(display, format, &mut out)?;
+    out.flush()
+}
+
+// Colour indices with each display pixel blown up to a scale x scale block.
+pub(crate) fn scaled_pixels(display: &Display, scale: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(display.pixels().len() * scale * scale);
+    for row in display.pixels().chunks(display.width()) {
+        let line: Vec<u8> = row.iter()
+            .flat_map(|&color| ::std::iter::repeat_n(color & 3, scale))
+            .collect();
+        for _ in 0..scale {
+            data.extend_from_slice(&line);
+        }
+    }
+    data
+}
+
+fn write_png<W: Write>(display: &Display, scale: u32, palette: &Palette, out: W) -> io::Result<()> {
+    let scale = scale.max(1) as usize;
+    let (width, height) = (display.width() * scale, display.height() * scale);
+    let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    let plte: Vec<u8> = palette.iter().flat_map(|&(r, g, b)| vec![r, g, b]).collect();
+    encoder.set_palette(plte);
+    let data = scaled_pixels(display, scale);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn write_pbm<W: Write>(display: &Display, mut out: W) -> io::Result<()> {
+    writeln!(out, "P1")?;
+    writeln!(out, "{} {}", display.width(), display.height())?;
+    // Plain PBM lines should stay under 70 characters, so write each row of
+    // a high-res display as two lines.
+    for row in display.pixels().chunks(display.width()) {
+        for half in row.chunks(64) {
+            let bits: Vec<&str> = half.iter().map(|&color| if color != 0 { "1" } else { "0" }).collect();
+            writeln!(out, "{}", bits.concat())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display() -> Display {
+        let mut display = Display::new(64, 32);
+        display.flip(0, 0, 1);
+        display.flip(63, 31, 3);
+        display
+    }
+
+    #[test]
+    fn writes_plain_pbm() {
+        let mut out = Vec::new();
+        write_image(&display(), ImageFormat::Pbm, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(&lines[..2], &["P1", "64 32"]);
+        assert_eq!(lines.len(), 2 + 32);
+        assert!(lines[2].starts_with("10"));
+        assert!(lines[33].ends_with("01"));
+    }
+    #[test]
+    fn writes_scaled_png() {
+        let mut out = Vec::new();
+        let palette = parse_palette("000000,ffffff,#FF0000,00ff00").unwrap();
+        write_image(&display(), ImageFormat::Png { scale: 3, palette }, &mut out).unwrap();
+        let decoder = png::Decoder::new(&out[..]);
+        let mut reader = decoder.read_info().unwrap();
+        assert_eq!((reader.info().width, reader.info().height), (192, 96));
+        assert_eq!(&reader.info().palette.as_ref().unwrap()[3..6], &[255, 255, 255]);
+        let mut data = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!(&data[..4], &[1, 1, 1, 0]);
+        assert_eq!(data[data.len() - 1], 3);
+    }
+    #[test]
+    fn rejects_bad_palettes() {
+        assert!(parse_palette("000000,ffffff").is_err());
+        assert!(parse_palette("000000,ffffff,ff0000,00ff0").is_err());
+    }
+}
@@ -1,22 +1,52 @@
 extern crate sdl2;
 
+use std::collections::VecDeque;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use frontend::Frontend;
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use frontend::{Frontend, Hotkey};
+use display::Display;
+use screenshot::DEFAULT_PALETTE;
+use sound::{Oscillator, Tone};
 
-use {DISPWIDTH, DISPHEIGHT, DISPSIZE, NUM_KEYS};
+use {DISPWIDTH, DISPHEIGHT, NUM_KEYS};
 
+// Window scale in low-res mode; high-res pixels are drawn at half this size.
 const SCALE: u32 = 10;
 
+pub const DEFAULT_TONE_HZ: f32 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+impl AudioCallback for Oscillator {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.fill(out);
+    }
+}
+
 pub struct SDL2Frontend {
     canvas: sdl2::render::Canvas<sdl2::video::Window>,
     event_pump: sdl2::EventPump,
+    audio_device: AudioDevice<Oscillator>,
+    // Debugger commands are typed on the console the emulator was started
+    // from; the reader thread is only started once the debugger asks.
+    debug_commands: Option<Receiver<String>>,
+    hotkeys: VecDeque<Hotkey>,
 }
 
 impl SDL2Frontend {
     pub fn new() -> Self {
+        Self::with_tone(DEFAULT_TONE_HZ, DEFAULT_VOLUME)
+    }
+    // frequency is in Hz; volume runs from 0.0 (silent) to 1.0.
+    pub fn with_tone(frequency: f32, volume: f32) -> Self {
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
@@ -31,28 +61,53 @@ impl SDL2Frontend {
         canvas.present();
         let event_pump = sdl_context.event_pump().unwrap();
 
-        SDL2Frontend {  canvas: canvas,
-                        event_pump: event_pump }
+        let audio_subsystem = sdl_context.audio().unwrap();
+        let desired_spec = AudioSpecDesired { freq: Some(44_100),
+                                              channels: Some(1),
+                                              samples: None };
+        // The device starts paused; start_tone() resumes it.
+        let audio_device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
+            Oscillator::new(frequency, volume, spec.freq as u32)
+        }).unwrap();
+
+        SDL2Frontend {  canvas,
+                        event_pump,
+                        audio_device,
+                        debug_commands: None,
+                        hotkeys: VecDeque::new() }
+    }
+}
+
+impl Default for SDL2Frontend {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Frontend for SDL2Frontend {
-    fn draw_graphics(&mut self, display: [bool; DISPSIZE]) {
-        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+    fn draw_graphics(&mut self, display: &Display) {
+        let scale = (DISPWIDTH as u32 * SCALE) / display.width() as u32;
+        let (r, g, b) = DEFAULT_PALETTE[0];
+        self.canvas.set_draw_color(Color::RGB(r, g, b));
         self.canvas.clear();
-        self.canvas.set_draw_color(Color::RGB(0, 230, 20));
-        for y in 0..DISPHEIGHT {
-            for x in 0..DISPWIDTH {
-                let this_index = y * DISPWIDTH + x;
-                if display[this_index] {
-                    self.canvas.fill_rect(Rect::new((x * SCALE as usize) as i32, (y * SCALE as usize) as i32, SCALE, SCALE)).unwrap();
+        for y in 0..display.height() {
+            for x in 0..display.width() {
+                let color = display.color(x, y);
+                if color != 0 {
+                    let (r, g, b) = DEFAULT_PALETTE[color as usize];
+                    self.canvas.set_draw_color(Color::RGB(r, g, b));
+                    self.canvas.fill_rect(Rect::new((x as u32 * scale) as i32, (y as u32 * scale) as i32, scale, scale)).unwrap();
                 }
             }
         }
         self.canvas.present();
     }
-    fn beep(&self) {
-        unimplemented!();
+    fn start_tone(&mut self, tone: Tone) {
+        self.audio_device.lock().set_tone(tone);
+        self.audio_device.resume();
+    }
+    fn stop_tone(&mut self) {
+        self.audio_device.pause();
     }
     fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]> {
         let mut result = [false; NUM_KEYS];
@@ -74,12 +129,24 @@ impl Frontend for SDL2Frontend {
                 Event::KeyDown { keycode: Some(Keycode::F), .. }     => { result[0xD] = true; },
                 Event::KeyDown { keycode: Some(Keycode::G), .. }     => { result[0xE] = true; },
                 Event::KeyDown { keycode: Some(Keycode::H), .. }     => { result[0xF] = true; },
+                Event::KeyDown { keycode: Some(Keycode::F1), .. }    => { self.hotkeys.push_back(Hotkey::SaveState(1)); },
+                Event::KeyDown { keycode: Some(Keycode::F2), .. }    => { self.hotkeys.push_back(Hotkey::SaveState(2)); },
+                Event::KeyDown { keycode: Some(Keycode::F3), .. }    => { self.hotkeys.push_back(Hotkey::SaveState(3)); },
+                Event::KeyDown { keycode: Some(Keycode::F4), .. }    => { self.hotkeys.push_back(Hotkey::SaveState(4)); },
+                Event::KeyDown { keycode: Some(Keycode::F5), .. }    => { self.hotkeys.push_back(Hotkey::LoadState(1)); },
+                Event::KeyDown { keycode: Some(Keycode::F6), .. }    => { self.hotkeys.push_back(Hotkey::LoadState(2)); },
+                Event::KeyDown { keycode: Some(Keycode::F7), .. }    => { self.hotkeys.push_back(Hotkey::LoadState(3)); },
+                Event::KeyDown { keycode: Some(Keycode::F8), .. }    => { self.hotkeys.push_back(Hotkey::LoadState(4)); },
+                Event::KeyDown { keycode: Some(Keycode::F12), .. }   => { self.hotkeys.push_back(Hotkey::Screenshot); },
                 Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::X), .. } => {
                     return None;
                 },
                 _ => {}
             }
         }
+        if self.event_pump.keyboard_state().is_scancode_pressed(Scancode::Backspace) {
+            self.hotkeys.push_back(Hotkey::Rewind);
+        }
         Some(result)
     }
     fn get_key(&mut self) -> Option<u8> {
@@ -110,4 +177,23 @@ impl Frontend for SDL2Frontend {
             }
         }
     }
-}
\ No newline at end of file
+    fn poll_hotkey(&mut self) -> Option<Hotkey> {
+        self.hotkeys.pop_front()
+    }
+    fn poll_debug_command(&mut self) -> Option<String> {
+        let receiver = self.debug_commands.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let stdin = io::stdin();
+                for line in stdin.lock().lines() {
+                    match line {
+                        Ok(line) => if sender.send(line).is_err() { break; },
+                        Err(_) => break,
+                    }
+                }
+            });
+            receiver
+        });
+        receiver.try_recv().ok()
+    }
+}
@@ -0,0 +1,63 @@
+// What the buzzer should sound like while it is on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tone {
+    // Classic CHIP-8: the frontend picks its own beep.
+    Buzzer,
+    // XO-CHIP: loop the 128-bit pattern, one bit per sample at sample_rate Hz.
+    Pattern { bits: [u8; 16], sample_rate: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoundEvent {
+    Start(Tone),
+    Stop,
+}
+
+// Plays either a square wave or a looping XO-CHIP bit pattern. phase runs
+// from 0 to 1 over one square wave period or one pass through the pattern.
+pub(crate) struct Oscillator {
+    square_phase_inc: f32,
+    phase_inc: f32,
+    phase: f32,
+    volume: f32,
+    pattern: Option<[u8; 16]>,
+    output_rate: f32,
+}
+
+impl Oscillator {
+    // frequency is the pitch of the classic buzzer; volume runs from 0 to 1.
+    pub(crate) fn new(frequency: f32, volume: f32, output_rate: u32) -> Self {
+        let output_rate = output_rate as f32;
+        Oscillator { square_phase_inc: frequency / output_rate,
+                     phase_inc: frequency / output_rate,
+                     phase: 0.0,
+                     volume: volume.clamp(0.0, 1.0),
+                     pattern: None,
+                     output_rate }
+    }
+    pub(crate) fn set_tone(&mut self, tone: Tone) {
+        match tone {
+            Tone::Buzzer => {
+                self.pattern = None;
+                self.phase_inc = self.square_phase_inc;
+            },
+            Tone::Pattern { bits, sample_rate } => {
+                self.pattern = Some(bits);
+                self.phase_inc = sample_rate as f32 / self.output_rate / 128.0;
+            },
+        }
+    }
+    pub(crate) fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            let high = match self.pattern {
+                Some(bits) => {
+                    let bit = (self.phase * 128.0) as usize % 128;
+                    bits[bit / 8] & (0x80 >> (bit % 8)) != 0
+                },
+                None => self.phase < 0.5,
+            };
+            *sample = if high { self.volume } else { -self.volume };
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use error::Chip8Error;
+use quirks::Quirks;
+
+// Save states start with this tag and a version byte. Bump the version
+// whenever the layout changes; old versions are refused rather than misread.
+pub(crate) const STATE_MAGIC: &[u8; 4] = b"C8ST";
+pub(crate) const STATE_VERSION: u8 = 1;
+
+// Appends fixed-size big-endian fields to a save state or movie.
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> Self {
+        StateWriter { bytes: Vec::new() }
+    }
+    pub(crate) fn header(&mut self, magic: &[u8; 4], version: u8) {
+        self.bytes.extend_from_slice(magic);
+        self.bytes.push(version);
+    }
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+    pub(crate) fn bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+    pub(crate) fn bytes(&mut self, values: &[u8]) {
+        self.bytes.extend_from_slice(values);
+    }
+    pub(crate) fn quirks(&mut self, quirks: &Quirks) {
+        self.bool(quirks.shift_uses_vx);
+        self.bool(quirks.load_store_increments_i);
+        self.bool(quirks.jump_uses_vx);
+        self.bool(quirks.vf_reset);
+        self.bool(quirks.clip_sprites);
+        self.bool(quirks.display_wait);
+    }
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// Reads back the fields written by StateWriter, in the same order. bad
+// turns a reason into the error for the kind of file being read.
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+    bad: fn(&'static str) -> Chip8Error,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], bad: fn(&'static str) -> Chip8Error) -> Self {
+        StateReader { bytes, bad }
+    }
+    pub(crate) fn error(&self, reason: &'static str) -> Chip8Error {
+        (self.bad)(reason)
+    }
+    pub(crate) fn header(&mut self, magic: &[u8; 4], version: u8) -> Result<(), Chip8Error> {
+        if self.bytes.len() < magic.len() + 1 || &self.bytes[..magic.len()] != magic {
+            return Err(self.error("unrecognised file type"));
+        }
+        if self.bytes[magic.len()] != version {
+            return Err(self.error("unsupported version"));
+        }
+        self.bytes = &self.bytes[magic.len() + 1..];
+        Ok(())
+    }
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], Chip8Error> {
+        if self.bytes.len() < len {
+            return Err(self.error("file is truncated"));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+    pub(crate) fn u8(&mut self) -> Result<u8, Chip8Error> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub(crate) fn u16(&mut self) -> Result<u16, Chip8Error> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+    pub(crate) fn u32(&mut self) -> Result<u32, Chip8Error> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64, Chip8Error> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+    pub(crate) fn bool(&mut self) -> Result<bool, Chip8Error> {
+        Ok(self.u8()? != 0)
+    }
+    pub(crate) fn array<A: Default + AsMut<[u8]>>(&mut self) -> Result<A, Chip8Error> {
+        let mut array = A::default();
+        let len = array.as_mut().len();
+        array.as_mut().copy_from_slice(self.bytes(len)?);
+        Ok(array)
+    }
+    pub(crate) fn quirks(&mut self) -> Result<Quirks, Chip8Error> {
+        Ok(Quirks { shift_uses_vx: self.bool()?,
+                    load_store_increments_i: self.bool()?,
+                    jump_uses_vx: self.bool()?,
+                    vf_reset: self.bool()?,
+                    clip_sprites: self.bool()?,
+                    display_wait: self.bool()?,
+        })
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+    pub(crate) fn finish(self) -> Result<(), Chip8Error> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(self.error("unexpected data at the end"))
+        }
+    }
+}
@@ -1,75 +1,177 @@
 extern crate termion;
 
+use std::collections::VecDeque;
 use std::io::{Read, Write, stdout, Stdout, Bytes};
 use termion::async_stdin;
 use termion::raw::IntoRawMode;
-use frontend::Frontend;
+use frontend::{Frontend, Hotkey};
+use display::Display;
+use sound::Tone;
 
-use {DISPWIDTH, DISPHEIGHT, DISPSIZE, NUM_KEYS};
+use {DISPWIDTH, DISPHEIGHT, NUM_KEYS};
+
+// Lines of debugger output kept on screen below the display.
+const DEBUG_LINES: usize = 10;
 
 pub struct TermionFrontend {
     output_stream: termion::raw::RawTerminal<Stdout>,
     input_stream:  Bytes<termion::AsyncReader>,
+    last_width:    usize,
+    last_height:   usize,
+    // Text typed after ':' is a debugger command rather than keypad input.
+    command_line:  Option<String>,
+    commands:      VecDeque<String>,
+    debug_lines:   VecDeque<String>,
+    hotkeys:       VecDeque<Hotkey>,
 }
 
 impl TermionFrontend {
+    // AsyncReader is already fed by a buffering thread, so reading it
+    // byte by byte is cheap.
+    #[allow(clippy::unbuffered_bytes)]
     pub fn new() -> Self {
         TermionFrontend{ output_stream: stdout().into_raw_mode().unwrap(),
-                         input_stream:  async_stdin().bytes()}
+                         input_stream:  async_stdin().bytes(),
+                         last_width:    DISPWIDTH,
+                         last_height:   DISPHEIGHT,
+                         command_line:  None,
+                         commands:      VecDeque::new(),
+                         debug_lines:   VecDeque::new(),
+                         hotkeys:       VecDeque::new()}
+    }
+    // Redraws the debugger output and prompt below the game display.
+    fn draw_debug_area(&mut self) {
+        let top = self.last_height as u16 + 3;
+        for (i, line) in self.debug_lines.iter().enumerate() {
+            write!(self.output_stream, "{}{}{}", termion::cursor::Goto(1, top + i as u16),
+                   termion::clear::CurrentLine, line).unwrap();
+        }
+        let prompt_row = top + DEBUG_LINES as u16;
+        write!(self.output_stream, "{}{}", termion::cursor::Goto(1, prompt_row),
+               termion::clear::CurrentLine).unwrap();
+        if let Some(ref line) = self.command_line {
+            write!(self.output_stream, ":{}", line).unwrap();
+        }
+        self.output_stream.flush().unwrap();
+    }
+    // F5-F8 send ESC [ 15 ~, 17 ~, 18 ~ and 19 ~, and F12 ESC [ 24 ~. The
+    // ESC [ and the first digit are already read.
+    fn read_function_key(&mut self, first: u8) {
+        let hotkey = match (first, self.input_stream.next()) {
+            (b'1', Some(Ok(b'5'))) => Hotkey::LoadState(1),
+            (b'1', Some(Ok(b'7'))) => Hotkey::LoadState(2),
+            (b'1', Some(Ok(b'8'))) => Hotkey::LoadState(3),
+            (b'1', Some(Ok(b'9'))) => Hotkey::LoadState(4),
+            (b'2', Some(Ok(b'4'))) => Hotkey::Screenshot,
+            _ => return,
+        };
+        if let Some(Ok(b'~')) = self.input_stream.next() {
+            self.hotkeys.push_back(hotkey);
+        }
+    }
+    fn edit_command_line(&mut self, ch: u8) {
+        match ch {
+            b'\r' | b'\n' => {
+                if let Some(line) = self.command_line.take() {
+                    self.commands.push_back(line);
+                }
+            },
+            b'\x1B' => { self.command_line = None; },
+            b'\x7F' | b'\x08' => {
+                if let Some(ref mut line) = self.command_line { line.pop(); }
+            },
+            ch => {
+                if let Some(ref mut line) = self.command_line { line.push(ch as char); }
+            },
+        }
+        self.draw_debug_area();
+    }
+}
+
+impl Default for TermionFrontend {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Frontend for TermionFrontend {
-    fn draw_graphics(&mut self, display: [bool; DISPSIZE]) {
-        let border_tile = "##";
-        let on_tile = "\u{2588}\u{2588}";
-        let off_tile = "  ";
+    fn draw_graphics(&mut self, display: &Display) {
+        // High-res pixels get one column each so the frame stays the same width.
+        let tile_width = if display.width() > DISPWIDTH { 1 } else { 2 };
+        let border_tile = "#".repeat(tile_width);
+        // One shade per combination of the two XO-CHIP bitplanes.
+        let tiles: Vec<String> = [" ", "\u{2588}", "\u{2592}", "\u{2593}"].iter()
+            .map(|shade| shade.repeat(tile_width))
+            .collect();
 
+        if display.width() != self.last_width {
+            write!(self.output_stream, "{}", termion::clear::All).unwrap();
+            self.last_width = display.width();
+            self.last_height = display.height();
+            if !self.debug_lines.is_empty() { self.draw_debug_area(); }
+        }
         write!(self.output_stream, "{}{}", termion::cursor::Goto(1, 1),
                                termion::cursor::Hide).unwrap();
 
         let mut header = String::new();
-        for _ in 0..DISPWIDTH+2 { header.push_str(border_tile); }
+        for _ in 0..display.width()+2 { header.push_str(&border_tile); }
         write!(self.output_stream, "{}\n\r", header).unwrap();
 
-        for row in 0..DISPHEIGHT {
+        for row in 0..display.height() {
             let mut this_row = String::new();
-            this_row.push_str(border_tile);
-            for col in 0..DISPWIDTH {
-                if display[row * DISPWIDTH + col] {
-                    this_row.push_str(on_tile);
-                } else {
-                    this_row.push_str(off_tile);
-                }
+            this_row.push_str(&border_tile);
+            for col in 0..display.width() {
+                this_row.push_str(&tiles[display.color(col, row) as usize]);
             }
-            this_row.push_str(border_tile);
+            this_row.push_str(&border_tile);
             write!(self.output_stream, "{}\n\r", this_row).unwrap();
         }
 
         let mut footer = String::new();
-        for _ in 0..DISPWIDTH+2 { footer.push_str(border_tile); }
+        for _ in 0..display.width()+2 { footer.push_str(&border_tile); }
         write!(self.output_stream, "{}\n\r", footer).unwrap();
     }
-    fn beep(&self) {
-        //panic!("bell not implemented in termion");
+    // A terminal can only ring its bell, so sound once at the start of a tone.
+    fn start_tone(&mut self, _tone: Tone) {
+        write!(self.output_stream, "\x07").unwrap();
+        self.output_stream.flush().unwrap();
     }
+    fn stop_tone(&mut self) {}
     fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]> {
         let mut result = [false; 16];
 
         let next_ch = self.input_stream.next();
         if let Some(Ok(ch)) = next_ch {
+            if self.command_line.is_some() {
+                self.edit_command_line(ch);
+                return Some(result);
+            }
             match ch {
+                b':' => {
+                    self.command_line = Some(String::new());
+                    self.draw_debug_area();
+                },
                 b'\x1B' => {
-                    if let Some(Ok(b'[')) = self.input_stream.next() {
-                        if let Some(Ok(ch2)) = self.input_stream.next() {
-                            match ch2 {
-                                65 => { result[0x2] = true; },
-                                68 => { result[0x4] = true; },
-                                67 => { result[0x6] = true; },
-                                66 => { result[0x8] = true; },
-                                _ => {},
+                    match self.input_stream.next() {
+                        Some(Ok(b'[')) => {
+                            if let Some(Ok(ch2)) = self.input_stream.next() {
+                                match ch2 {
+                                    65 => { result[0x2] = true; },
+                                    68 => { result[0x4] = true; },
+                                    67 => { result[0x6] = true; },
+                                    66 => { result[0x8] = true; },
+                                    b'1' | b'2' => self.read_function_key(ch2),
+                                    _ => {},
+                                }
                             }
-                        }
+                        },
+                        // F1-F4 send ESC O P to ESC O S.
+                        Some(Ok(b'O')) => {
+                            if let Some(Ok(ch2 @ b'P'..=b'S')) = self.input_stream.next() {
+                                self.hotkeys.push_back(Hotkey::SaveState(ch2 - b'P' + 1));
+                            }
+                        },
+                        _ => {},
                     }
                 }
                 b'q' => { result[0x0] = true; },
@@ -84,6 +186,9 @@ impl Frontend for TermionFrontend {
                 b'f' => { result[0xD] = true; },
                 b'g' => { result[0xE] = true; },
                 b'h' => { result[0xF] = true; },
+                // Terminals send no key releases, so holding backspace
+                // rewinds at the keyboard's repeat rate.
+                b'\x7F' | b'\x08' => { self.hotkeys.push_back(Hotkey::Rewind); },
                 b'x' => { return None; }
                 _ => {},
             }
@@ -145,4 +250,19 @@ impl Frontend for TermionFrontend {
             }
         }
     }
-}
\ No newline at end of file
+    fn poll_hotkey(&mut self) -> Option<Hotkey> {
+        self.hotkeys.pop_front()
+    }
+    fn poll_debug_command(&mut self) -> Option<String> {
+        self.commands.pop_front()
+    }
+    fn print_debug(&mut self, text: &str) {
+        for line in text.lines() {
+            self.debug_lines.push_back(line.to_string());
+            if self.debug_lines.len() > DEBUG_LINES {
+                self.debug_lines.pop_front();
+            }
+        }
+        self.draw_debug_area();
+    }
+}
@@ -0,0 +1,289 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::path::Path;
+
+use gif;
+use display::Display;
+use frontend::{Frontend, Hotkey};
+use screenshot::{scaled_pixels, Palette};
+use sound::Tone;
+
+use {HIRES_WIDTH, HIRES_HEIGHT, NUM_KEYS};
+
+// GIF delays are in hundredths of a second, and most viewers slow frames
+// shorter than two hundredths right down, so quicker updates are merged.
+const MIN_GIF_DELAY: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoFormat {
+    // Animated GIF holding one frame per display update.
+    Gif,
+    // Uncompressed YUV4MPEG2 at a constant 60 frames a second, for piping
+    // into an encoder such as ffmpeg.
+    Y4m,
+}
+
+impl VideoFormat {
+    // Picks the format from a file name ending in .gif or .y4m.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "gif" => Some(VideoFormat::Gif),
+            "y4m" => Some(VideoFormat::Y4m),
+            _ => None,
+        }
+    }
+}
+
+fn gif_error(err: gif::EncodingError) -> io::Error {
+    match err {
+        gif::EncodingError::Io(err) => err,
+        gif::EncodingError::Format(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+// BT.601 studio range, as most encoders expect from y4m.
+fn ycbcr((r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let y = 16.0 + 0.257 * r + 0.504 * g + 0.098 * b;
+    let cb = 128.0 - 0.148 * r - 0.291 * g + 0.439 * b;
+    let cr = 128.0 + 0.439 * r - 0.368 * g - 0.071 * b;
+    (y.round() as u8, cb.round() as u8, cr.round() as u8)
+}
+
+enum Sink<W: Write> {
+    // The newest picture is held back until the next one shows how long it
+    // stayed on screen.
+    Gif { encoder: Option<gif::Encoder<W>>, pending: Option<(Vec<u8>, u64)> },
+    // The last picture is repeated until the display changes.
+    Y4m { out: W, header_written: bool, last: Vec<u8> },
+}
+
+// Turns the display, sampled once per 60 Hz frame, into a video. The picture
+// is the size of a high-res display at the given scale, so low-res pixels are
+// drawn twice as big and the size stays the same when a game switches modes.
+pub struct VideoEncoder<W: Write> {
+    sink: Sink<W>,
+    scale: usize,
+    palette: Palette,
+    frame: u64,
+}
+
+impl<W: Write> VideoEncoder<W> {
+    // scale is the size of a high-res pixel.
+    pub fn new(out: W, format: VideoFormat, scale: u32, palette: Palette) -> io::Result<Self> {
+        let scale = scale.max(1) as usize;
+        let sink = match format {
+            VideoFormat::Gif => {
+                let plte: Vec<u8> = palette.iter().flat_map(|&(r, g, b)| vec![r, g, b]).collect();
+                let (width, height) = ((HIRES_WIDTH * scale) as u16, (HIRES_HEIGHT * scale) as u16);
+                let mut encoder = gif::Encoder::new(out, width, height, &plte).map_err(gif_error)?;
+                encoder.set_repeat(gif::Repeat::Infinite).map_err(gif_error)?;
+                Sink::Gif { encoder: Some(encoder), pending: None }
+            },
+            VideoFormat::Y4m => Sink::Y4m { out, header_written: false, last: Vec::new() },
+        };
+        Ok(VideoEncoder { sink, scale, palette, frame: 0 })
+    }
+    fn picture(&self, display: &Display) -> Vec<u8> {
+        let scale = self.scale * HIRES_WIDTH / display.width();
+        scaled_pixels(display, scale)
+    }
+    // Hundredths of a second from the start to the beginning of a frame.
+    fn centiseconds(frame: u64) -> u64 {
+        (frame * 100 + 30) / 60
+    }
+    // Call once for every 60 Hz frame, with drawn set if the display changed
+    // during it. y4m output gets every frame; GIF output only the changes.
+    pub fn frame(&mut self, display: &Display, drawn: bool) -> io::Result<()> {
+        let frame = self.frame;
+        self.frame += 1;
+        let picture = if drawn || frame == 0 { Some(self.picture(display)) } else { None };
+        let (width, height) = (HIRES_WIDTH * self.scale, HIRES_HEIGHT * self.scale);
+        match self.sink {
+            Sink::Gif { ref mut encoder, ref mut pending } => {
+                let picture = match picture {
+                    Some(picture) => picture,
+                    None => return Ok(()),
+                };
+                if let Some((ref mut held, start)) = *pending {
+                    let delay = Self::centiseconds(frame) - Self::centiseconds(start);
+                    if delay < MIN_GIF_DELAY {
+                        *held = picture;
+                        return Ok(());
+                    }
+                    write_gif_frame(encoder, held, width, height, delay)?;
+                }
+                *pending = Some((picture, frame));
+            },
+            Sink::Y4m { ref mut out, ref mut header_written, ref mut last } => {
+                if !*header_written {
+                    writeln!(out, "YUV4MPEG2 W{} H{} F60:1 Ip A1:1 C444", width, height)?;
+                    *header_written = true;
+                }
+                if let Some(picture) = picture {
+                    *last = picture;
+                }
+                let colours: Vec<(u8, u8, u8)> = self.palette.iter().map(|&rgb| ycbcr(rgb)).collect();
+                out.write_all(b"FRAME\n")?;
+                out.write_all(&last.iter().map(|&i| colours[i as usize].0).collect::<Vec<u8>>())?;
+                out.write_all(&last.iter().map(|&i| colours[i as usize].1).collect::<Vec<u8>>())?;
+                out.write_all(&last.iter().map(|&i| colours[i as usize].2).collect::<Vec<u8>>())?;
+            },
+        }
+        Ok(())
+    }
+    // Writes out the last picture and the end of the file.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let (width, height) = (HIRES_WIDTH * self.scale, HIRES_HEIGHT * self.scale);
+        let end = Self::centiseconds(self.frame);
+        match self.sink {
+            Sink::Gif { ref mut encoder, ref mut pending } => {
+                if let Some((held, start)) = pending.take() {
+                    let delay = (end - Self::centiseconds(start)).max(MIN_GIF_DELAY);
+                    write_gif_frame(encoder, &held, width, height, delay)?;
+                }
+                if let Some(encoder) = encoder.take() {
+                    encoder.into_inner()?.flush()?;
+                }
+                Ok(())
+            },
+            Sink::Y4m { ref mut out, .. } => out.flush(),
+        }
+    }
+}
+
+fn write_gif_frame<W: Write>(encoder: &mut Option<gif::Encoder<W>>, picture: &[u8],
+                             width: usize, height: usize, delay: u64) -> io::Result<()> {
+    let encoder = match encoder.as_mut() {
+        Some(encoder) => encoder,
+        None => return Ok(()),
+    };
+    let frame = gif::Frame { width: width as u16,
+                             height: height as u16,
+                             delay: delay.min(u16::MAX as u64) as u16,
+                             buffer: Cow::Borrowed(picture),
+                             ..gif::Frame::default() };
+    encoder.write_frame(&frame).map_err(gif_error)
+}
+
+// Wraps a front end and records what it is asked to draw. The video is
+// finished when the recorder is dropped.
+pub struct VideoRecorder<F: Frontend, W: Write> {
+    inner: F,
+    encoder: Option<VideoEncoder<W>>,
+    display: Display,
+    drawn: bool,
+}
+
+impl<F: Frontend, W: Write> VideoRecorder<F, W> {
+    pub fn new(encoder: VideoEncoder<W>, inner: F) -> Self {
+        VideoRecorder { inner,
+                        encoder: Some(encoder),
+                        display: Display::new(HIRES_WIDTH / 2, HIRES_HEIGHT / 2),
+                        drawn: false,
+        }
+    }
+    fn stop(&mut self, why: io::Error) {
+        self.encoder = None;
+        self.inner.print_debug(&format!("video recording stopped: {}\n", why));
+    }
+}
+
+impl<F: Frontend, W: Write> Drop for VideoRecorder<F, W> {
+    fn drop(&mut self) {
+        if let Some(mut encoder) = self.encoder.take() {
+            if let Err(why) = encoder.finish() {
+                eprintln!("couldn't finish video: {}", why);
+            }
+        }
+    }
+}
+
+impl<F: Frontend, W: Write> Frontend for VideoRecorder<F, W> {
+    fn draw_graphics(&mut self, display: &Display) {
+        self.display = display.clone();
+        self.drawn = true;
+        self.inner.draw_graphics(display);
+    }
+    fn start_tone(&mut self, tone: Tone) {
+        self.inner.start_tone(tone);
+    }
+    fn stop_tone(&mut self) {
+        self.inner.stop_tone();
+    }
+    // Called once at the end of every frame, so this is where time moves on.
+    fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]> {
+        let result = match self.encoder {
+            Some(ref mut encoder) => encoder.frame(&self.display, self.drawn),
+            None => Ok(()),
+        };
+        if let Err(why) = result { self.stop(why); }
+        self.drawn = false;
+        self.inner.check_keys()
+    }
+    fn get_key(&mut self) -> Option<u8> {
+        self.inner.get_key()
+    }
+    fn poll_debug_command(&mut self) -> Option<String> {
+        self.inner.poll_debug_command()
+    }
+    fn poll_hotkey(&mut self) -> Option<Hotkey> {
+        self.inner.poll_hotkey()
+    }
+    fn print_debug(&mut self, text: &str) {
+        self.inner.print_debug(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screenshot::DEFAULT_PALETTE;
+
+    #[test]
+    fn gif_frames_follow_display_updates() {
+        let mut out = Vec::new();
+        {
+            let mut encoder = VideoEncoder::new(&mut out, VideoFormat::Gif, 1, DEFAULT_PALETTE).unwrap();
+            let mut display = Display::new(64, 32);
+            // The first frame is always kept; the display then changes on
+            // frames 1, 2 and 6.
+            for frame in 0..12 {
+                let drawn = frame == 1 || frame == 2 || frame == 6;
+                if drawn {
+                    display.flip(frame, 0, 1);
+                }
+                encoder.frame(&display, drawn).unwrap();
+            }
+            encoder.finish().unwrap();
+        }
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(&out[..]).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (128, 64));
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        // Frame 2 comes a hundredth of a second after frame 1, so it replaces
+        // it; the delays still add up to 12 frames at 60 Hz.
+        assert_eq!(delays, vec![2, 8, 10]);
+    }
+    #[test]
+    fn y4m_has_a_frame_per_tick() {
+        let mut out = Vec::new();
+        {
+            let mut encoder = VideoEncoder::new(&mut out, VideoFormat::Y4m, 1, DEFAULT_PALETTE).unwrap();
+            let display = Display::new(128, 64);
+            for _ in 0..3 {
+                encoder.frame(&display, false).unwrap();
+            }
+            encoder.finish().unwrap();
+        }
+        let header = b"YUV4MPEG2 W128 H64 F60:1 Ip A1:1 C444\n";
+        assert!(out.starts_with(header));
+        assert_eq!(out.len(), header.len() + 3 * (6 + 3 * 128 * 64));
+        assert_eq!(out[header.len() + 6], 16);
+    }
+}
@@ -0,0 +1,205 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+use display::Display;
+use frontend::{Frontend, Hotkey};
+use sound::{Oscillator, Tone};
+
+use NUM_KEYS;
+
+pub const WAV_SAMPLE_RATE: u32 = 44_100;
+
+// 44.1 kHz divides evenly into 60 Hz frames, so the audio never drifts away
+// from a video recorded alongside it.
+const SAMPLES_PER_FRAME: usize = WAV_SAMPLE_RATE as usize / 60;
+
+// Size of the RIFF header in front of the samples.
+const HEADER_BYTES: u32 = 44;
+
+// Renders the buzzer, sampled once per 60 Hz frame, to 16-bit mono PCM. The
+// sizes in the header are only filled in by finish(), hence the Seek.
+pub struct WavEncoder<W: Write + Seek> {
+    out: W,
+    oscillator: Oscillator,
+    tone: Option<Tone>,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavEncoder<W> {
+    // frequency is the pitch of the classic buzzer; volume runs from 0 to 1.
+    pub fn new(mut out: W, frequency: f32, volume: f32) -> io::Result<Self> {
+        write_header(&mut out, 0)?;
+        Ok(WavEncoder { out,
+                        oscillator: Oscillator::new(frequency, volume, WAV_SAMPLE_RATE),
+                        tone: None,
+                        samples: 0,
+        })
+    }
+    // Call once for every 60 Hz frame with what the buzzer played during it,
+    // or None while it was quiet.
+    pub fn frame(&mut self, tone: Option<Tone>) -> io::Result<()> {
+        let mut samples = [0.0; SAMPLES_PER_FRAME];
+        if let Some(tone) = tone {
+            if self.tone != Some(tone) {
+                self.oscillator.set_tone(tone);
+            }
+            self.oscillator.fill(&mut samples);
+        }
+        self.tone = tone;
+        let mut bytes = Vec::with_capacity(SAMPLES_PER_FRAME * 2);
+        for sample in samples.iter() {
+            bytes.extend_from_slice(&((sample * i16::MAX as f32) as i16).to_le_bytes());
+        }
+        self.out.write_all(&bytes)?;
+        self.samples += SAMPLES_PER_FRAME as u32;
+        Ok(())
+    }
+    // Fills in the header now that the length is known.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.out, self.samples * 2)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+}
+
+fn write_header<W: Write>(out: &mut W, data_bytes: u32) -> io::Result<()> {
+    out.write_all(b"RIFF")?;
+    out.write_all(&(HEADER_BYTES - 8 + data_bytes).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // Uncompressed PCM, one channel.
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&WAV_SAMPLE_RATE.to_le_bytes())?;
+    out.write_all(&(WAV_SAMPLE_RATE * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_bytes.to_le_bytes())
+}
+
+// Wraps a front end and records what it is asked to play. The file is
+// finished when the recorder is dropped.
+pub struct WavRecorder<F: Frontend, W: Write + Seek> {
+    inner: F,
+    encoder: Option<WavEncoder<W>>,
+    tone: Option<Tone>,
+}
+
+impl<F: Frontend, W: Write + Seek> WavRecorder<F, W> {
+    pub fn new(encoder: WavEncoder<W>, inner: F) -> Self {
+        WavRecorder { inner, encoder: Some(encoder), tone: None }
+    }
+    fn stop(&mut self, why: io::Error) {
+        self.encoder = None;
+        self.inner.print_debug(&format!("audio recording stopped: {}\n", why));
+    }
+}
+
+impl<F: Frontend, W: Write + Seek> Drop for WavRecorder<F, W> {
+    fn drop(&mut self) {
+        if let Some(mut encoder) = self.encoder.take() {
+            if let Err(why) = encoder.finish() {
+                eprintln!("couldn't finish audio: {}", why);
+            }
+        }
+    }
+}
+
+impl<F: Frontend, W: Write + Seek> Frontend for WavRecorder<F, W> {
+    fn draw_graphics(&mut self, display: &Display) {
+        self.inner.draw_graphics(display);
+    }
+    fn start_tone(&mut self, tone: Tone) {
+        self.tone = Some(tone);
+        self.inner.start_tone(tone);
+    }
+    fn stop_tone(&mut self) {
+        self.tone = None;
+        self.inner.stop_tone();
+    }
+    // Called once at the end of every frame, so this is where time moves on.
+    fn check_keys(&mut self) -> Option<[bool; NUM_KEYS]> {
+        let result = match self.encoder {
+            Some(ref mut encoder) => encoder.frame(self.tone),
+            None => Ok(()),
+        };
+        if let Err(why) = result { self.stop(why); }
+        self.inner.check_keys()
+    }
+    fn get_key(&mut self) -> Option<u8> {
+        self.inner.get_key()
+    }
+    fn poll_debug_command(&mut self) -> Option<String> {
+        self.inner.poll_debug_command()
+    }
+    fn poll_hotkey(&mut self) -> Option<Hotkey> {
+        self.inner.poll_hotkey()
+    }
+    fn print_debug(&mut self, text: &str) {
+        self.inner.print_debug(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use cpu::Chip8;
+    use headless_frontend::HeadlessFrontend;
+    use quirks::Quirks;
+    use sound::SoundEvent;
+
+    fn samples(wav: &[u8]) -> Vec<i16> {
+        wav[HEADER_BYTES as usize..].chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect()
+    }
+
+    #[test]
+    fn square_wave_at_the_given_pitch() {
+        let mut out = Vec::new();
+        {
+            let mut encoder = WavEncoder::new(Cursor::new(&mut out), 441.0, 0.5).unwrap();
+            encoder.frame(Some(Tone::Buzzer)).unwrap();
+            encoder.frame(None).unwrap();
+            encoder.finish().unwrap();
+        }
+        assert_eq!(&out[..4], b"RIFF");
+        assert_eq!(&out[40..44], &(4 * SAMPLES_PER_FRAME as u32).to_le_bytes());
+        let samples = samples(&out);
+        assert_eq!(samples.len(), 2 * SAMPLES_PER_FRAME);
+        // 441 Hz at 44.1 kHz is 50 samples high, then 50 low; the phase adds
+        // up in floating point, so the edges may land a sample either way.
+        assert!(samples[..49].iter().all(|&sample| sample == i16::MAX / 2));
+        assert!(samples[51..99].iter().all(|&sample| sample == -(i16::MAX / 2)));
+        assert!(samples[SAMPLES_PER_FRAME..].iter().all(|&sample| sample == 0));
+    }
+    #[test]
+    fn sound_timer_sets_how_long_the_buzzer_sounds() {
+        let mut out = Vec::new();
+        let mut chip8 = Chip8::new(Quirks::default());
+        // LD VA, 10; LD ST, VA; JP 0x204
+        chip8.load(vec![0x6A, 0x0A, 0xFA, 0x18, 0x12, 0x04]).unwrap();
+        {
+            let encoder = WavEncoder::new(Cursor::new(&mut out), 440.0, 0.25).unwrap();
+            let mut recorder = WavRecorder::new(encoder, HeadlessFrontend::default());
+            for _ in 0..20 {
+                for _ in 0..8 {
+                    chip8.emulate_cycle().unwrap();
+                }
+                chip8.tick_timers();
+                match chip8.poll_sound_event() {
+                    Some(SoundEvent::Start(tone)) => recorder.start_tone(tone),
+                    Some(SoundEvent::Stop) => recorder.stop_tone(),
+                    None => {},
+                }
+                recorder.check_keys();
+            }
+        }
+        let loud: Vec<bool> = samples(&out).chunks(SAMPLES_PER_FRAME)
+            .map(|frame| frame.iter().any(|&sample| sample != 0))
+            .collect();
+        assert_eq!(loud.len(), 20);
+        assert_eq!(loud.iter().filter(|&&loud| loud).count(), 9);
+        assert!(loud[..9].iter().all(|&loud| loud));
+    }
+}
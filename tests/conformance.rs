@@ -0,0 +1,86 @@
+// Runs Timendus' CHIP-8 test suite (https://github.com/Timendus/chip8-test-suite)
+// under each quirks profile it has a platform for, and reads the ticks and
+// crosses it draws. The suite is GPL-3.0, so it is not vendored here: point
+// CHIP8_TEST_SUITE at a directory holding its .ch8 files to run these tests.
+// Without it they pass after printing that they were skipped.
+
+extern crate rschip8;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use rschip8::*;
+
+const FRAMES: u64 = 600;
+// Fast enough that the quirks test does not complain about speed.
+const CYCLES_PER_FRAME: usize = 1000;
+
+// The profiles the suite's quirks test knows, with the menu choice for each.
+// It has no platform that behaves like CHIP-48.
+const PLATFORMS: [(&str, u8); 3] = [("vip", 1), ("schip", 2), ("xochip", 3)];
+
+fn suite_rom(name: &str) -> Option<Vec<u8>> {
+    let dir = match env::var_os("CHIP8_TEST_SUITE") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            eprintln!("skipping {}: set CHIP8_TEST_SUITE to the directory holding the suite's ROMs", name);
+            return None;
+        },
+    };
+    let path = dir.join(name);
+    match fs::read(&path) {
+        Ok(rom) => Some(rom),
+        Err(why) => panic!("couldn't read {}: {}", path.display(), why),
+    }
+}
+
+// Runs a suite ROM under each platform and checks that it drew at least one
+// tick and no crosses; on a mismatch shows what it drew.
+fn check(name: &str, menu_choice: bool) {
+    let rom = match suite_rom(name) {
+        Some(rom) => rom,
+        None => return,
+    };
+    let mut failures = Vec::new();
+    for &(profile, choice) in PLATFORMS.iter() {
+        let quirks = Quirks::from_name(profile).unwrap();
+        let display = run_suite_rom(&rom, quirks, CYCLES_PER_FRAME, FRAMES,
+                                    if menu_choice { Some(choice) } else { None }).unwrap();
+        let results: Vec<&str> = find_glyphs(&display, &SUITE_GLYPHS).iter().map(|found| found.name).collect();
+        if results.is_empty() || results.contains(&"fail") {
+            failures.push(format!("{} with --quirks {}: got {:?}\n{}", name, profile, results, display.to_text()));
+        }
+    }
+    if !failures.is_empty() {
+        panic!("{}", failures.join("\n"));
+    }
+}
+
+#[test]
+fn ibm_logo() {
+    let rom = match suite_rom("2-ibm-logo.ch8") {
+        Some(rom) => rom,
+        None => return,
+    };
+    // The logo has no result to read; it must draw without faulting.
+    for &(profile, _) in PLATFORMS.iter() {
+        let display = run_suite_rom(&rom, Quirks::from_name(profile).unwrap(), CYCLES_PER_FRAME, FRAMES, None).unwrap();
+        assert!(display.pixels().iter().any(|&pixel| pixel != 0), "with --quirks {}: nothing drawn", profile);
+    }
+}
+
+#[test]
+fn opcodes() {
+    check("3-corax+.ch8", false);
+}
+
+#[test]
+fn flags() {
+    check("4-flags.ch8", false);
+}
+
+#[test]
+fn quirks_match_profile() {
+    check("5-quirks.ch8", true);
+}
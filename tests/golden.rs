@@ -0,0 +1,115 @@
+// Runs the bundled games headlessly and compares the last frame with the one
+// checked in under tests/golden. After a change that is meant to alter what a
+// game draws, look over the diffs and then regenerate the frames with
+//
+//     UPDATE_GOLDEN=1 cargo test --test golden
+
+extern crate rschip8;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rschip8::*;
+
+const SEED: u64 = 0xC8;
+// The default 500 Hz clock.
+const CYCLES_PER_FRAME: usize = 8;
+
+struct Game {
+    rom: &'static str,
+    frames: u64,
+    keys: &'static str,
+}
+
+const GAMES: &[Game] = &[
+    Game { rom: "15PUZZLE", frames: 300, keys: "60:4,120:8,180:1" },
+    Game { rom: "BLINKY", frames: 600, keys: "100-200:3,300-400:6" },
+    Game { rom: "BRIX", frames: 600, keys: "60-150:4,300-420:6" },
+    Game { rom: "INVADERS", frames: 240, keys: "30:5,100-160:4,200:5" },
+    // KALEID moves one step per key press rather than while a key is held.
+    Game { rom: "KALEID", frames: 200, keys: "10:2,14:2,18:2,22:2,26:2,30:2,34:4,38:4,42:4,46:4,50:4,54:4,\
+                                               58:8,62:8,66:8,70:8,74:8,78:8,82:6,86:6,90:6,94:6,98:6,102:6" },
+    Game { rom: "MAZE", frames: 120, keys: "" },
+    Game { rom: "PONG", frames: 600, keys: "60-120:1,200-260:4,300-400:C" },
+    Game { rom: "TANK", frames: 600, keys: "60-100:8,150:5,200-260:6,300:5" },
+    Game { rom: "TETRIS", frames: 600, keys: "60-64:5,100:4,200-230:6,300-330:7" },
+    Game { rom: "UFO", frames: 600, keys: "60:5,200:4,350:6" },
+    Game { rom: "WIPEOFF", frames: 600, keys: "60-200:4,300-500:6" },
+];
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+// Runs the game as --headless does: the keypad is read at the end of each
+// frame, and a game waiting for a key gets the next one in the script.
+fn run(game: &Game) -> Display {
+    let rom = fs::read(root().join("games").join(game.rom)).unwrap();
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load(rom).unwrap();
+    chip8.seed_rng(SEED);
+    let frontend = HeadlessFrontend::new(parse_key_script(game.keys).unwrap(), Some(game.frames));
+    run_headless(&mut chip8, frontend, CYCLES_PER_FRAME, None).unwrap();
+    chip8.display().clone()
+}
+
+// Lists the rows that differ, with the expected row, the actual one and a
+// line of carets under the pixels that changed.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut report = String::new();
+    if expected.len() != actual.len() {
+        report += &format!("expected {} rows, got {}\n", expected.len(), actual.len());
+    }
+    for (row, (want, got)) in expected.iter().zip(actual.iter()).enumerate() {
+        if want == got { continue; }
+        let marks: String = want.chars().zip(got.chars())
+            .map(|(a, b)| if a == b { ' ' } else { '^' })
+            .collect();
+        report += &format!("row {:2} expected {}\n       actual   {}\n                {}\n",
+                           row, want, got, marks.trim_end());
+    }
+    report
+}
+
+fn check(game: &Game) -> Result<(), String> {
+    let actual = run(game).to_text();
+    let path = root().join("tests").join("golden").join(format!("{}.txt", game.rom));
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &actual).unwrap();
+        return Ok(());
+    }
+    let expected = match fs::read_to_string(&path) {
+        Ok(expected) => expected,
+        Err(why) => return Err(format!("{}: couldn't read {}: {}", game.rom, relative(&path), why)),
+    };
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(format!("{} differs from {} after {} frames:\n{}",
+                    game.rom, relative(&path), game.frames, diff(&expected, &actual)))
+    }
+}
+
+fn relative(path: &Path) -> String {
+    path.strip_prefix(root()).unwrap_or(path).display().to_string()
+}
+
+#[test]
+fn games_match_golden_frames() {
+    // Check every game before failing, so one run shows all the breakage.
+    let failures: Vec<String> = GAMES.iter().filter_map(|game| check(game).err()).collect();
+    if !failures.is_empty() {
+        panic!("{} of {} games drew something unexpected\n\n{}",
+               failures.len(), GAMES.len(), failures.join("\n"));
+    }
+}
+
+#[test]
+fn diff_points_at_changed_pixels() {
+    let report = diff("....\n.##.\n", "....\n.#..\n");
+    assert_eq!(report, "row  1 expected .##.\n       actual   .#..\n                  ^\n");
+}
@@ -0,0 +1,368 @@
+// Shared by the property tests in tests/properties.rs and the fuzz targets
+// in fuzz/, which include this file by path.
+//
+// Reference is a second, deliberately plain implementation of the classic
+// CHIP-8 instructions, written from the instruction set rather than from
+// src/cpu.rs, so that the interpreter can be checked against it one
+// instruction at a time. SUPER-CHIP and XO-CHIP instructions are left out; a
+// comparison simply stops when it reaches one.
+
+#![allow(dead_code)]
+
+use rschip8::{decode_opcode, Chip8, Quirks, Rng};
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 32;
+
+// One fuzzing session: a ROM, the quirks and seed to run it with, and the
+// keys held during each frame as a bit mask. A game waiting on FX0A is
+// given the lowest key held in the current frame.
+#[derive(Debug, Clone)]
+pub struct Input {
+    pub rom: Vec<u8>,
+    pub quirks: Quirks,
+    pub seed: u64,
+    pub frames: Vec<u16>,
+}
+
+pub const PROFILES: [&str; 4] = ["vip", "chip48", "schip", "xochip"];
+pub const CYCLES_PER_FRAME: usize = 8;
+
+impl Input {
+    // Splits raw fuzzer bytes into a profile byte, an 8 byte seed, a frame
+    // count byte, two bytes per frame and then the ROM.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut data = data.iter().cloned();
+        let profile = data.next().unwrap_or(0) as usize % PROFILES.len();
+        let seed = (0..8).fold(0u64, |seed, _| seed << 8 | data.next().unwrap_or(0) as u64);
+        let count = data.next().unwrap_or(0) as usize;
+        let frames = (0..count)
+            .map(|_| (data.next().unwrap_or(0) as u16) << 8 | data.next().unwrap_or(0) as u16)
+            .collect();
+        Input { rom: data.collect(),
+                quirks: Quirks::from_name(PROFILES[profile]).unwrap(),
+                seed,
+                frames,
+        }
+    }
+}
+
+fn key_for(mask: u16) -> u8 {
+    (mask.trailing_zeros() & 0xF) as u8
+}
+
+fn keys_from(mask: u16) -> [bool; 16] {
+    let mut keys = [false; 16];
+    for (key, pressed) in keys.iter_mut().enumerate() {
+        *pressed = mask & (1 << key) != 0;
+    }
+    keys
+}
+
+// Runs the input without checking anything but that the interpreter does not
+// panic. Errors skip the faulting instruction and carry on, as the debugger
+// allows, so a bad opcode does not end the run early. Returns the number of
+// instructions run.
+pub fn run_unchecked(input: &Input) -> usize {
+    let mut chip8 = Chip8::new(input.quirks);
+    chip8.seed_rng(input.seed);
+    let mut rom = input.rom.clone();
+    rom.truncate(0x10000 - 0x200);
+    chip8.load(rom).unwrap();
+    let mut count = 0;
+    for &mask in input.frames.iter() {
+        for _ in 0..CYCLES_PER_FRAME {
+            let _ = chip8.emulate_cycle();
+            count += 1;
+            if chip8.waiting_for_key().is_some() {
+                chip8.give_key(key_for(mask));
+            }
+        }
+        chip8.tick_timers();
+        chip8.poll_sound_event();
+        chip8.set_keys(keys_from(mask));
+    }
+    // The machine must also survive a trip through a save state.
+    let mut copy = Chip8::new(input.quirks);
+    copy.load_state(&chip8.save_state()).unwrap();
+    count
+}
+
+pub enum Outcome {
+    Ran,
+    // The instruction cannot run: a bad address or stack overflow.
+    Fault,
+    // Not a classic CHIP-8 instruction.
+    Unsupported,
+}
+
+pub struct Reference {
+    pub v: [u8; 16],
+    pub i: usize,
+    pub pc: usize,
+    pub stack: Vec<usize>,
+    pub delay: u8,
+    pub sound: u8,
+    pub keys: [bool; 16],
+    pub memory: Vec<u8>,
+    pub screen: [[bool; WIDTH]; HEIGHT],
+    pub waiting: Option<usize>,
+    quirks: Quirks,
+    drawn: bool,
+    rng: Rng,
+}
+
+impl Reference {
+    // Starts from a copy of the interpreter's memory, which already holds the
+    // font and the ROM.
+    pub fn new(memory: &[u8], quirks: Quirks, seed: u64) -> Self {
+        Reference { v: [0; 16],
+                    i: 0,
+                    pc: 0x200,
+                    stack: Vec::new(),
+                    delay: 0,
+                    sound: 0,
+                    keys: [false; 16],
+                    memory: memory.to_vec(),
+                    screen: [[false; WIDTH]; HEIGHT],
+                    waiting: None,
+                    quirks,
+                    drawn: false,
+                    rng: Rng::new(seed),
+        }
+    }
+
+    // The end of a 60 Hz frame. Key presses are latched until a program
+    // looks at the key, as the interpreter does, so that a press between two
+    // checks is not lost.
+    pub fn end_frame(&mut self, keys: [bool; 16]) {
+        self.drawn = false;
+        self.delay = self.delay.saturating_sub(1);
+        self.sound = self.sound.saturating_sub(1);
+        for (latched, &pressed) in self.keys.iter_mut().zip(keys.iter()) {
+            *latched |= pressed;
+        }
+    }
+
+    fn skip_if(&mut self, condition: bool) {
+        if !condition { return; }
+        // XO-CHIP's F000 NNNN is four bytes long and skipped whole.
+        let long = self.memory.get(self.pc) == Some(&0xF0) && self.memory.get(self.pc + 1) == Some(&0x00);
+        self.pc = (self.pc + if long { 4 } else { 2 }) & 0xFFFF;
+    }
+
+    pub fn step(&mut self) -> Outcome {
+        if self.pc + 1 >= self.memory.len() { return Outcome::Fault; }
+        let opcode = (self.memory[self.pc] as u16) << 8 | self.memory[self.pc + 1] as u16;
+        let x = (opcode >> 8 & 0xF) as usize;
+        let y = (opcode >> 4 & 0xF) as usize;
+        let n = (opcode & 0xF) as usize;
+        let nn = opcode as u8;
+        let nnn = (opcode & 0xFFF) as usize;
+        self.pc = (self.pc + 2) & 0xFFFF;
+        match opcode >> 12 {
+            0x0 => match opcode {
+                0x00E0 => self.screen = [[false; WIDTH]; HEIGHT],
+                0x00EE => match self.stack.pop() {
+                    Some(addr) => self.pc = addr,
+                    None => return Outcome::Fault,
+                },
+                _ => return Outcome::Unsupported,
+            },
+            0x1 => self.pc = nnn,
+            0x2 => {
+                if self.stack.len() == 16 { return Outcome::Fault; }
+                self.stack.push(self.pc);
+                self.pc = nnn;
+            },
+            0x3 => { let skip = self.v[x] == nn; self.skip_if(skip) },
+            0x4 => { let skip = self.v[x] != nn; self.skip_if(skip) },
+            0x5 if n == 0 => { let skip = self.v[x] == self.v[y]; self.skip_if(skip) },
+            0x6 => self.v[x] = nn,
+            0x7 => self.v[x] = self.v[x].wrapping_add(nn),
+            0x8 => {
+                let (vx, vy) = (self.v[x], self.v[y]);
+                let (result, flag) = match n {
+                    0x0 => (vy, None),
+                    0x1 => (vx | vy, if self.quirks.vf_reset { Some(0) } else { None }),
+                    0x2 => (vx & vy, if self.quirks.vf_reset { Some(0) } else { None }),
+                    0x3 => (vx ^ vy, if self.quirks.vf_reset { Some(0) } else { None }),
+                    0x4 => (vx.wrapping_add(vy), Some((vx as u16 + vy as u16 > 0xFF) as u8)),
+                    0x5 => (vx.wrapping_sub(vy), Some((vx >= vy) as u8)),
+                    0x7 => (vy.wrapping_sub(vx), Some((vy >= vx) as u8)),
+                    0x6 => {
+                        let source = if self.quirks.shift_uses_vx { vx } else { vy };
+                        (source >> 1, Some(source & 1))
+                    },
+                    0xE => {
+                        let source = if self.quirks.shift_uses_vx { vx } else { vy };
+                        (source << 1, Some(source >> 7))
+                    },
+                    _ => return Outcome::Unsupported,
+                };
+                self.v[x] = result;
+                if let Some(flag) = flag { self.v[0xF] = flag; }
+            },
+            0x9 if n == 0 => { let skip = self.v[x] != self.v[y]; self.skip_if(skip) },
+            0xA => self.i = nnn,
+            0xB => {
+                let offset = if self.quirks.jump_uses_vx { self.v[x] } else { self.v[0] };
+                self.pc = nnn + offset as usize;
+            },
+            0xC => self.v[x] = self.rng.next_u8() & nn,
+            0xD if n > 0 => {
+                if self.quirks.display_wait && self.drawn {
+                    self.pc = self.pc.wrapping_sub(2) & 0xFFFF;
+                    return Outcome::Ran;
+                }
+                self.drawn = true;
+                let (left, top) = (self.v[x] as usize % WIDTH, self.v[y] as usize % HEIGHT);
+                let mut collision = false;
+                for row in 0..n {
+                    let py = top + row;
+                    if py >= HEIGHT && self.quirks.clip_sprites { break; }
+                    let sprite = match self.memory.get(self.i + row) {
+                        Some(&sprite) => sprite,
+                        None => return Outcome::Fault,
+                    };
+                    for col in 0..8 {
+                        let px = left + col;
+                        if px >= WIDTH && self.quirks.clip_sprites { break; }
+                        if sprite & (0x80 >> col) == 0 { continue; }
+                        let pixel = &mut self.screen[py % HEIGHT][px % WIDTH];
+                        collision |= *pixel;
+                        *pixel = !*pixel;
+                    }
+                }
+                self.v[0xF] = collision as u8;
+            },
+            0xE if nn == 0x9E || nn == 0xA1 => {
+                let key = (self.v[x] & 0xF) as usize;
+                let pressed = self.keys[key];
+                self.keys[key] = false;
+                self.skip_if(pressed == (nn == 0x9E));
+            },
+            0xF => match nn {
+                0x07 => self.v[x] = self.delay,
+                0x0A => self.waiting = Some(x),
+                0x15 => self.delay = self.v[x],
+                0x18 => self.sound = self.v[x],
+                0x1E => self.i = (self.i + self.v[x] as usize) & 0xFFFF,
+                0x29 => self.i = (self.v[x] & 0xF) as usize * 5,
+                0x33 => {
+                    if self.i + 2 >= self.memory.len() { return Outcome::Fault; }
+                    let value = self.v[x];
+                    self.memory[self.i] = value / 100;
+                    self.memory[self.i + 1] = value / 10 % 10;
+                    self.memory[self.i + 2] = value % 10;
+                },
+                0x55 | 0x65 => {
+                    if self.i + x >= self.memory.len() { return Outcome::Fault; }
+                    for reg in 0..x + 1 {
+                        if nn == 0x55 {
+                            self.memory[self.i + reg] = self.v[reg];
+                        } else {
+                            self.v[reg] = self.memory[self.i + reg];
+                        }
+                    }
+                    if self.quirks.load_store_increments_i { self.i = (self.i + x + 1) & 0xFFFF; }
+                },
+                _ => return Outcome::Unsupported,
+            },
+            _ => return Outcome::Unsupported,
+        }
+        Outcome::Ran
+    }
+}
+
+fn mismatch(chip8: &Chip8, reference: &Reference) -> Option<String> {
+    let differs = |what: &str, ours: String, theirs: String| {
+        Some(format!("{} is {} but should be {}", what, ours, theirs))
+    };
+    if chip8.registers() != reference.v {
+        return differs("V0-VF", format!("{:?}", chip8.registers()), format!("{:?}", reference.v));
+    }
+    if chip8.index() != reference.i {
+        return differs("I", format!("{:#X}", chip8.index()), format!("{:#X}", reference.i));
+    }
+    if chip8.pc() != reference.pc {
+        return differs("PC", format!("{:#X}", chip8.pc()), format!("{:#X}", reference.pc));
+    }
+    if chip8.stack() != &reference.stack[..] {
+        return differs("the stack", format!("{:X?}", chip8.stack()), format!("{:X?}", reference.stack));
+    }
+    if (chip8.delay_timer(), chip8.sound_timer()) != (reference.delay, reference.sound) {
+        return differs("DT, ST", format!("{:?}", (chip8.delay_timer(), chip8.sound_timer())),
+                       format!("{:?}", (reference.delay, reference.sound)));
+    }
+    if chip8.keys() != reference.keys {
+        return differs("the keypad", format!("{:?}", chip8.keys()), format!("{:?}", reference.keys));
+    }
+    if chip8.memory() != &reference.memory[..] {
+        let addr = (0..reference.memory.len()).find(|&addr| chip8.memory()[addr] != reference.memory[addr]).unwrap();
+        return differs(&format!("memory at {:#X}", addr), chip8.memory()[addr].to_string(),
+                       reference.memory[addr].to_string());
+    }
+    for (y, row) in reference.screen.iter().enumerate() {
+        for (x, &lit) in row.iter().enumerate() {
+            if chip8.display().get(x, y) != lit {
+                return differs(&format!("pixel ({}, {})", x, y), (!lit).to_string(), lit.to_string());
+            }
+        }
+    }
+    None
+}
+
+// Runs the input on the interpreter and the reference side by side and
+// compares the whole machine after every instruction, until the program
+// reaches an instruction the reference leaves out or one that faults.
+// Returns the number of instructions compared.
+pub fn compare(input: &Input) -> Result<usize, String> {
+    let mut chip8 = Chip8::new(input.quirks);
+    chip8.seed_rng(input.seed);
+    let mut rom = input.rom.clone();
+    rom.truncate(0x10000 - 0x200);
+    chip8.load(rom).unwrap();
+    let mut reference = Reference::new(chip8.memory(), input.quirks, input.seed);
+    let mut count = 0;
+    for (frame, &mask) in input.frames.iter().enumerate() {
+        for _ in 0..CYCLES_PER_FRAME {
+            let address = chip8.pc();
+            let opcode = chip8.memory().get(address..address + 2)
+                .map_or(0, |bytes| (bytes[0] as u16) << 8 | bytes[1] as u16);
+            let result = chip8.emulate_cycle();
+            match reference.step() {
+                Outcome::Unsupported => {
+                    if decode_opcode(opcode).is_none() && result.is_ok() {
+                        return Err(format!("{:04X} at {:#X} is not an instruction but ran", opcode, address));
+                    }
+                    return Ok(count);
+                },
+                Outcome::Fault => {
+                    if result.is_ok() {
+                        return Err(format!("{:04X} at {:#X} should have failed", opcode, address));
+                    }
+                    return Ok(count);
+                },
+                Outcome::Ran => {
+                    if let Err(why) = result {
+                        return Err(format!("{:04X} at {:#X} failed: {}", opcode, address, why));
+                    }
+                },
+            }
+            if chip8.waiting_for_key().is_some() {
+                let key = key_for(mask);
+                chip8.give_key(key);
+                if let Some(x) = reference.waiting.take() { reference.v[x] = key; }
+            }
+            count += 1;
+            if let Some(why) = mismatch(&chip8, &reference) {
+                return Err(format!("after {:04X} at {:#X} in frame {}: {}", opcode, address, frame, why));
+            }
+        }
+        chip8.tick_timers();
+        chip8.set_keys(keys_from(mask));
+        reference.end_frame(keys_from(mask));
+    }
+    Ok(count)
+}
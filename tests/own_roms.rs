@@ -0,0 +1,102 @@
+// Runs this project's own test ROMs in tests/roms under every quirks profile
+// and reads the results they draw. They assemble with the built-in assembler
+// and check the emulator against this project's reading of the spec, so they
+// are extra coverage, not a conformance check; tests/conformance.rs runs
+// Timendus' suite for that.
+
+extern crate rschip8;
+
+use std::fs;
+use std::path::PathBuf;
+
+use rschip8::*;
+
+const FRAMES: u64 = 120;
+// Quick enough that a ROM never waits long on a timer, as the test ROMs
+// recommend.
+const CYCLES_PER_FRAME: usize = 30;
+
+const PROFILES: [&str; 4] = ["vip", "chip48", "schip", "xochip"];
+
+const LOGO: Glyph = Glyph { name: "IBM logo", rows: &["#######..######...##...##",
+                                                      ".........................",
+                                                      "..###.....##..##..###.###",
+                                                      ".........................",
+                                                      "..###.....#####...##.#.##",
+                                                      ".........................",
+                                                      "..###.....##..##..##...##",
+                                                      ".........................",
+                                                      "#######..######...##...##"] };
+
+fn rom(name: &str) -> Vec<u8> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms").join(name);
+    let source = fs::read_to_string(&path).unwrap();
+    match assemble(&source) {
+        Ok(rom) => rom,
+        Err(why) => panic!("couldn't assemble {}: {}", name, why),
+    }
+}
+
+fn screen(name: &str, profile: &str, keys: &str) -> Display {
+    let quirks = Quirks::from_name(profile).unwrap();
+    run_test_rom(&rom(name), quirks, CYCLES_PER_FRAME, FRAMES, parse_key_script(keys).unwrap()).unwrap()
+}
+
+// Checks the results a ROM drew under each profile, and on a mismatch shows
+// what it drew.
+fn check<E>(name: &str, keys: &str, expected: E)
+    where E: Fn(Quirks) -> Vec<&'static str> {
+    let mut failures = Vec::new();
+    for profile in PROFILES.iter() {
+        let display = screen(name, profile, keys);
+        let expected = expected(Quirks::from_name(profile).unwrap());
+        let results = read_results(&display);
+        if results != expected {
+            failures.push(format!("{} with --quirks {}: expected {:?}, got {:?}\n{}",
+                                  name, profile, expected, results, display.to_text()));
+        }
+    }
+    if !failures.is_empty() {
+        panic!("{}", failures.join("\n"));
+    }
+}
+
+fn all_pass(count: usize) -> impl Fn(Quirks) -> Vec<&'static str> {
+    move |_| vec!["pass"; count]
+}
+
+#[test]
+fn ibm_logo() {
+    for profile in PROFILES.iter() {
+        let display = screen("ibm.src", profile, "");
+        assert_eq!(find_glyphs(&display, &[LOGO]), vec![GlyphMatch { name: "IBM logo", x: 20, y: 12 }],
+                   "with --quirks {}:\n{}", profile, display.to_text());
+    }
+}
+
+#[test]
+fn flags() {
+    check("flags.src", "", all_pass(12));
+}
+
+#[test]
+fn opcodes() {
+    check("opcode.src", "", all_pass(25));
+}
+
+#[test]
+fn keypad() {
+    check("keypad.src", "5:5,30-40:A", all_pass(4));
+}
+
+#[test]
+fn quirks_match_profile() {
+    check("quirks.src", "", |quirks| {
+        [quirks.vf_reset,
+         quirks.load_store_increments_i,
+         quirks.display_wait,
+         quirks.clip_sprites,
+         quirks.shift_uses_vx,
+         quirks.jump_uses_vx].iter().map(|&on| if on { "on" } else { "off" }).collect()
+    });
+}
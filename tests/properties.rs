@@ -0,0 +1,98 @@
+// Property tests over generated programs: the interpreter must survive any
+// bytes at all, and on programs made of classic CHIP-8 instructions it must
+// agree with the reference interpreter in tests/harness after every step.
+// The fuzz targets in fuzz/ run the same checks for much longer.
+
+extern crate proptest;
+extern crate rschip8;
+
+mod harness;
+
+use proptest::prelude::*;
+use rschip8::Quirks;
+
+use harness::{compare, run_unchecked, Input, PROFILES};
+
+fn quirks() -> impl Strategy<Value = Quirks> {
+    (0..PROFILES.len()).prop_map(|profile| Quirks::from_name(PROFILES[profile]).unwrap())
+}
+
+// Keys are held in about one frame in four, so games see both states.
+fn frames(count: usize) -> impl Strategy<Value = Vec<u16>> {
+    prop::collection::vec(prop_oneof![3 => Just(0u16), 1 => any::<u16>()], 1..count)
+}
+
+// A classic instruction with its operands. Jumps and calls stay inside the
+// program and I points into it or the font, so that programs run for a while
+// and stores change the code that follows. Returns are rare, since most
+// would find the stack empty.
+fn core_opcode() -> impl Strategy<Value = u16> {
+    let near = (0x200u16..0x280).prop_map(|addr| addr & !1);
+    let data = 0u16..0x300;
+    prop_oneof![
+        4 => Just(0x00E0u16),
+        1 => Just(0x00EE),
+        4 => near.clone().prop_map(|addr| 0x1000 | addr),
+        4 => near.clone().prop_map(|addr| 0x2000 | addr),
+        4 => (0u16..0x10, any::<u8>()).prop_map(|(x, nn)| 0x3000 | x << 8 | nn as u16),
+        4 => (0u16..0x10, any::<u8>()).prop_map(|(x, nn)| 0x4000 | x << 8 | nn as u16),
+        4 => (0u16..0x10, 0u16..0x10).prop_map(|(x, y)| 0x5000 | x << 8 | y << 4),
+        4 => (0u16..0x10, any::<u8>()).prop_map(|(x, nn)| 0x6000 | x << 8 | nn as u16),
+        4 => (0u16..0x10, any::<u8>()).prop_map(|(x, nn)| 0x7000 | x << 8 | nn as u16),
+        4 => (0u16..0x10, 0u16..0x10, prop::sample::select(vec![0u16, 1, 2, 3, 4, 5, 6, 7, 0xE]))
+            .prop_map(|(x, y, n)| 0x8000 | x << 8 | y << 4 | n),
+        4 => (0u16..0x10, 0u16..0x10).prop_map(|(x, y)| 0x9000 | x << 8 | y << 4),
+        4 => data.prop_map(|addr| 0xA000 | addr),
+        4 => near.prop_map(|addr| 0xB000 | addr),
+        4 => (0u16..0x10, any::<u8>()).prop_map(|(x, nn)| 0xC000 | x << 8 | nn as u16),
+        4 => (0u16..0x10, 0u16..0x10, 1u16..0x10).prop_map(|(x, y, n)| 0xD000 | x << 8 | y << 4 | n),
+        4 => (0u16..0x10, prop::sample::select(vec![0x9Eu16, 0xA1])).prop_map(|(x, nn)| 0xE000 | x << 8 | nn),
+        4 => (0u16..0x10, prop::sample::select(vec![0x07u16, 0x0A, 0x15, 0x18, 0x1E, 0x29, 0x33, 0x55, 0x65]))
+            .prop_map(|(x, nn)| 0xF000 | x << 8 | nn),
+    ]
+}
+
+// Exactly fills 0x200-0x27F, where the jumps land.
+fn core_program() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(core_opcode(), 64)
+        .prop_map(|opcodes| opcodes.iter().flat_map(|opcode| opcode.to_be_bytes().to_vec()).collect())
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes_never_panic(rom in prop::collection::vec(any::<u8>(), 0..512),
+                                   quirks in quirks(),
+                                   seed in any::<u64>(),
+                                   frames in frames(60)) {
+        run_unchecked(&Input { rom, quirks, seed, frames });
+    }
+
+    #[test]
+    fn core_instructions_match_reference(rom in core_program(),
+                                         quirks in quirks(),
+                                         seed in any::<u64>(),
+                                         frames in frames(60)) {
+        if let Err(why) = compare(&Input { rom, quirks, seed, frames }) {
+            return Err(TestCaseError::fail(why));
+        }
+    }
+}
+
+#[test]
+fn raw_input_is_split_into_fields() {
+    let input = Input::from_bytes(&[1, 0, 0, 0, 0, 0, 0, 0, 7, 2, 0x80, 0x01, 0x00, 0x20, 0x60, 0x05]);
+    assert_eq!(input.quirks, Quirks::chip48());
+    assert_eq!(input.seed, 7);
+    assert_eq!(input.frames, vec![0x8001, 0x0020]);
+    assert_eq!(input.rom, vec![0x60, 0x05]);
+    // Running out of bytes leaves the rest empty rather than failing.
+    assert!(Input::from_bytes(&[]).frames.is_empty());
+}
+
+#[test]
+fn reference_agrees_on_a_known_program() {
+    // LD V0, 5; LD F, V0; DRW V0, V0, 5; LD I, 0x300; LD B, V0; JP 0x20A
+    let rom = vec![0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0xA3, 0x00, 0xF0, 0x33, 0x12, 0x0A];
+    let input = Input { rom, quirks: Quirks::default(), seed: 0, frames: vec![0; 10] };
+    assert_eq!(compare(&input), Ok(80));
+}